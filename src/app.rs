@@ -1,3 +1,4 @@
+use crate::config::Config;
 use crate::pty::Pty;
 use crate::term::Term;
 use crate::utils::{parse_geometry, epoch_ms};
@@ -57,7 +58,7 @@ pub struct App {
 
 impl App {
     pub fn new(
-        geometry: Option<&str>, font: Option<&str>, log: Option<&str>
+        geometry: Option<&str>, font: Option<&str>, log: Option<&str>, config: Config
     ) -> Result<Self> {
         let log = match log {
             Some(x) => Some(File::create(x)?),
@@ -71,10 +72,12 @@ impl App {
         set_sigchld();
 
         let term = Term::new(cols, rows)?;
+        let mut win = Win::new(term.cols, term.rows, xoff, yoff, font)?;
+        win.set_format_other_keys(config.format_other_keys);
         Ok(App {
-            win: Win::new(term.cols, term.rows, xoff, yoff, font)?,
+            win,
             pty: Pty::new(term.cols, term.rows)?,
-            vte: Vte::new(),
+            vte: Vte::new(&config),
             term,
             log,
         })
@@ -143,7 +146,7 @@ impl App {
             }
 
             self.win.draw(&mut self.term);
-            timeout = TimeVal::milliseconds(next_blink_timeout());
+            timeout = TimeVal::milliseconds(self.win.next_timeout());
             delay_start = 0;
         }
 
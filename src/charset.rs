@@ -54,6 +54,7 @@ impl Charset {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct CharsetTable {
     charsets: [Charset; 4],
     current: usize,
@@ -0,0 +1,132 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+
+// max number of zero-width chars attached to one cell
+const CLUSTER_CHARS_MAX: usize = 16;
+// number of clusters before the first collection
+const CLUSTER_GC_MIN: usize = 1024;
+
+// Interned zero-width chars (combining marks, ZWJ sequences, variation
+// selectors) attached to the base char of a glyph.  Id 0 is the empty
+// cluster, so that plain glyphs need no lookup.
+pub struct ClusterTable {
+    strs: Vec<String>,
+    ids: HashMap<String, u32>,
+    // ids dropped by retain(), reused by push()
+    free: Vec<u32>,
+    // number of clusters at which they are collected next
+    gc_at: usize,
+}
+
+impl ClusterTable {
+    pub fn new() -> Self {
+        ClusterTable {
+            strs: vec![String::new()],
+            ids: HashMap::new(),
+            free: Vec::new(),
+            gc_at: CLUSTER_GC_MIN,
+        }
+    }
+
+    pub fn get(&self, id: u32) -> &str {
+        &self.strs[id as usize]
+    }
+
+    // Get the id of cluster id with c appended.
+    pub fn push(&mut self, id: u32, c: char) -> u32 {
+        let mut s = self.get(id).to_string();
+        if s.chars().count() >= CLUSTER_CHARS_MAX {
+            return id;
+        }
+        s.push(c);
+
+        if let Some(&id) = self.ids.get(&s) {
+            return id;
+        }
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.strs[id as usize] = s.clone();
+                id
+            }
+            None => {
+                self.strs.push(s.clone());
+                self.strs.len() as u32 - 1
+            }
+        };
+        self.ids.insert(s, id);
+        id
+    }
+
+    // Whether there are enough new clusters to collect the unused ones.
+    pub fn needs_gc(&self) -> bool {
+        self.ids.len() >= self.gc_at
+    }
+
+    // Drop the clusters not in used, their ids are reused.
+    pub fn retain(&mut self, used: &HashSet<u32>) {
+        let (strs, free) = (&mut self.strs, &mut self.free);
+        self.ids.retain(|_, id| {
+            let keep = used.contains(id);
+            if !keep {
+                strs[*id as usize] = String::new();
+                free.push(*id);
+            }
+            keep
+        });
+        self.gc_at = cmp::max(CLUSTER_GC_MIN, self.ids.len() * 2);
+    }
+}
+
+// Format chars that only affect how a cluster is shaped, fonts have no
+// glyph for them.
+pub fn is_invisible(c: char) -> bool {
+    matches!(c,
+        '\u{200b}'..='\u{200f}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{fe00}'..='\u{fe0f}'
+        | '\u{e0100}'..='\u{e01ef}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_interns_clusters() {
+        let mut table = ClusterTable::new();
+        let a = table.push(0, '\u{301}');
+        let b = table.push(a, '\u{302}');
+        assert_eq!(table.get(b), "\u{301}\u{302}");
+        assert_eq!(table.push(0, '\u{301}'), a);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn retain_reuses_ids() {
+        let mut table = ClusterTable::new();
+        let a = table.push(0, '\u{301}');
+        let b = table.push(0, '\u{302}');
+        table.retain(&HashSet::from([b]));
+        assert_eq!(table.get(b), "\u{302}");
+        assert_eq!(table.push(0, '\u{303}'), a);
+        assert_eq!(table.get(a), "\u{303}");
+        assert_eq!(table.push(0, '\u{302}'), b);
+    }
+
+    #[test]
+    fn collect_when_full() {
+        let mut table = ClusterTable::new();
+        for c in ('\u{4e00}'..).take(CLUSTER_GC_MIN - 1) {
+            table.push(0, c);
+        }
+        assert!(!table.needs_gc());
+        table.push(0, 'x');
+        assert!(table.needs_gc());
+
+        table.retain(&HashSet::from([1, 2]));
+        assert!(!table.needs_gc());
+        assert_eq!(table.get(2), "\u{4e01}");
+        assert_eq!(table.get(3), "");
+    }
+}
@@ -0,0 +1,33 @@
+// Settings chosen on the command line.
+
+use clap::ValueEnum;
+
+// access to the clipboard with OSC 52
+#[derive(Clone, Copy, PartialEq, PartialOrd, ValueEnum)]
+pub enum ClipboardPolicy {
+    /// Applications may not use the clipboard
+    Deny,
+    /// Applications may set the clipboard
+    AllowWrite,
+    /// Applications may set and also read the clipboard
+    AllowRead,
+}
+
+// files read by the kitty graphics protocol
+#[derive(Clone, Copy, PartialEq, PartialOrd, ValueEnum)]
+pub enum FilePolicy {
+    /// Images may only be sent as data
+    Deny,
+    /// Images may be sent in temp files, which are removed after reading
+    Temp,
+    /// Images may also be read from any file
+    Allow,
+}
+
+#[derive(Clone, Copy)]
+pub struct Config {
+    pub clipboard: ClipboardPolicy,
+    pub kitty_files: FilePolicy,
+    // xterm formatOtherKeys, 0 for "CSI 27;mod;code~", 1 for "CSI code;mod u"
+    pub format_other_keys: u8,
+}
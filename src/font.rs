@@ -1,8 +1,9 @@
 use crate::glyph::GlyphAttr;
 use crate::x11_wrapper as x11;
 
+use std::collections::HashMap;
 use std::ffi::CString;
-use std::os::raw::c_int;
+use std::os::raw::{c_int, c_uint};
 
 use anyhow::Result;
 
@@ -19,11 +20,17 @@ pub struct Font {
     bfont: x11::XftFont,
     ifont: x11::XftFont,
     ibfont: x11::XftFont,
+    // the parsed font name, used to look up fallback fonts
+    pattern: x11::FcPattern,
+    fallbacks: Vec<(GlyphAttr, x11::XftFont)>,
+    // the fallback of each style and char looked up, None if no font has it
+    found: HashMap<(GlyphAttr, char), Option<usize>>,
 }
 
 impl Font {
     pub fn new(dpy: x11::Display, scr: c_int, name: &str) -> Result<Self> {
         let pattern = x11::XftNameParse(name)?;
+        let base = x11::FcPatternDuplicate(pattern);
 
         let matched = x11::XftFontMatch(dpy, scr, pattern)?;
         let font = x11::XftFontOpenPattern(dpy, matched)?;
@@ -65,6 +72,9 @@ impl Font {
             bfont,
             ifont,
             ibfont,
+            pattern: base,
+            fallbacks: Vec::new(),
+            found: HashMap::new(),
         })
     }
 
@@ -81,6 +91,79 @@ impl Font {
         self.font
     }
 
+    // Find the font and glyph index to draw c with.  Chars missing in the
+    // main fonts are looked up in the system fonts, like st does, and the
+    // result is kept so that each char is only looked up once.
+    pub fn lookup(
+        &mut self, dpy: x11::Display, scr: c_int, attr: GlyphAttr, c: char
+    ) -> (x11::XftFont, c_uint) {
+        let font = self.get(attr);
+        let idx = x11::XftCharIndex(dpy, font, c);
+        if idx != 0 {
+            return (font, idx);
+        }
+
+        let style = attr & (GlyphAttr::BOLD | GlyphAttr::ITALIC);
+        let found = match self.found.get(&(style, c)) {
+            Some(&found) => found,
+            None => {
+                let found = self.find_fallback(dpy, scr, style, c);
+                self.found.insert((style, c), found);
+                found
+            }
+        };
+        match found {
+            Some(i) => {
+                let f = self.fallbacks[i].1;
+                (f, x11::XftCharIndex(dpy, f, c))
+            }
+            None => (font, idx),
+        }
+    }
+
+    // Get the index of the fallback font with c, a new one is only kept if
+    // it has c.
+    fn find_fallback(
+        &mut self, dpy: x11::Display, scr: c_int, style: GlyphAttr, c: char
+    ) -> Option<usize> {
+        let found = self.fallbacks.iter()
+            .position(|&(s, f)| s == style && x11::XftCharExists(dpy, f, c));
+        if found.is_some() {
+            return found;
+        }
+
+        let slant = CString::new("slant").unwrap();
+        let weight = CString::new("weight").unwrap();
+        let charset = CString::new("charset").unwrap();
+        let scalable = CString::new("scalable").unwrap();
+
+        let pattern = x11::FcPatternDuplicate(self.pattern);
+        if style.contains(GlyphAttr::ITALIC) {
+            x11::FcPatternDel(pattern, &slant);
+            x11::FcPatternAddInteger(pattern, &slant, x11::FC_SLANT_ITALIC);
+        }
+        if style.contains(GlyphAttr::BOLD) {
+            x11::FcPatternDel(pattern, &weight);
+            x11::FcPatternAddInteger(pattern, &weight, x11::FC_WEIGHT_BOLD);
+        }
+        let chars = x11::FcCharSetCreate();
+        x11::FcCharSetAddChar(chars, c);
+        x11::FcPatternAddCharSet(pattern, &charset, chars);
+        x11::FcPatternAddBool(pattern, &scalable, true);
+
+        let matched = x11::XftFontMatch(dpy, scr, pattern);
+        x11::FcCharSetDestroy(chars);
+        x11::FcPatternDestroy(pattern);
+        let fallback = matched.and_then(|m| x11::XftFontOpenPattern(dpy, m)).ok()?;
+        if !x11::XftCharExists(dpy, fallback, c) {
+            x11::XftFontClose(dpy, fallback);
+            return None;
+        }
+
+        self.fallbacks.push((style, fallback));
+        Some(self.fallbacks.len() - 1)
+    }
+
     pub fn size(&self) -> (usize, usize) {
         (self.width, self.height)
     }
@@ -1,12 +1,13 @@
 use crate::color::{BG_COLOR, FG_COLOR};
+use crate::image::ImageCell;
 
 use std::mem;
 
 use bitflags::bitflags;
 
 bitflags! {
-    #[derive(Clone, Copy, Debug, PartialEq)]
-    pub struct GlyphAttr: u16 {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct GlyphAttr: u32 {
         // font modifiers
         const BOLD       = 1 << 0;
         const FAINT      = 1 << 1;
@@ -28,6 +29,62 @@ bitflags! {
         const WIDE       = 1 << 9;
         // dummy for wide chars
         const DUMMY      = 1 << 10;
+
+        // style of UNDERLINE, none of the bits is a single underline
+        const UNDERLINE_STYLE  = 7 << 11;
+        const UNDERLINE_DOUBLE = 1 << 11;
+        const UNDERLINE_CURLY  = 2 << 11;
+        const UNDERLINE_DOTTED = 3 << 11;
+        const UNDERLINE_DASHED = 4 << 11;
+
+        const OVERLINE    = 1 << 14;
+        const RAPID_BLINK = 1 << 15;
+
+        // DECSCA, not erased by selective erase
+        const PROTECTED   = 1 << 16;
+
+        // attributes set by SGR
+        const SGR_MASK   = Self::FONT_MASK.bits() | Self::UNDERLINE_STYLE.bits()
+            | Self::OVERLINE.bits() | Self::RAPID_BLINK.bits();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum UnderlineStyle {
+    Single,
+    Double,
+    Curly,
+    Dotted,
+    Dashed,
+}
+
+impl GlyphAttr {
+    pub fn underline_style(&self) -> Option<UnderlineStyle> {
+        if !self.contains(GlyphAttr::UNDERLINE) {
+            return None;
+        }
+        let style = match *self & GlyphAttr::UNDERLINE_STYLE {
+            GlyphAttr::UNDERLINE_DOUBLE => UnderlineStyle::Double,
+            GlyphAttr::UNDERLINE_CURLY => UnderlineStyle::Curly,
+            GlyphAttr::UNDERLINE_DOTTED => UnderlineStyle::Dotted,
+            GlyphAttr::UNDERLINE_DASHED => UnderlineStyle::Dashed,
+            _ => UnderlineStyle::Single,
+        };
+        Some(style)
+    }
+
+    // Set the underline style of "SGR 4:n", 0 removes the underline.
+    pub fn set_underline(&mut self, n: u16) {
+        self.remove(GlyphAttr::UNDERLINE | GlyphAttr::UNDERLINE_STYLE);
+        let style = match n {
+            0 => return,
+            2 => GlyphAttr::UNDERLINE_DOUBLE,
+            3 => GlyphAttr::UNDERLINE_CURLY,
+            4 => GlyphAttr::UNDERLINE_DOTTED,
+            5 => GlyphAttr::UNDERLINE_DASHED,
+            _ => GlyphAttr::empty(),
+        };
+        self.insert(GlyphAttr::UNDERLINE | style);
     }
 }
 
@@ -35,18 +92,21 @@ bitflags! {
 pub struct GlyphProp {
     pub fg: usize,
     pub bg: usize,
+    // underline color, None follows fg
+    pub ul: Option<usize>,
     pub attr: GlyphAttr,
 }
 
 impl GlyphProp {
     pub fn new(fg: usize, bg: usize, attr: GlyphAttr) -> Self {
-        Self { fg, bg, attr }
+        Self { fg, bg, ul: None, attr }
     }
 
     pub fn reset(&mut self) {
         self.fg = FG_COLOR;
         self.bg = BG_COLOR;
-        self.attr -= GlyphAttr::FONT_MASK;
+        self.ul = None;
+        self.attr -= GlyphAttr::SGR_MASK;
     }
 
     pub fn reset_fg(&mut self) {
@@ -59,7 +119,8 @@ impl GlyphProp {
 
     pub fn resolve(&self, reverse: bool) -> Self {
         let (mut fg, mut bg) = (self.fg, self.bg);
-        let mut attr = self.attr & GlyphAttr::FONT_MASK;
+        let mut attr = self.attr
+            & (GlyphAttr::SGR_MASK | GlyphAttr::WIDE | GlyphAttr::DUMMY);
 
         if reverse ^ attr.contains(GlyphAttr::REVERSE) {
             mem::swap(&mut fg, &mut bg);
@@ -70,13 +131,17 @@ impl GlyphProp {
             attr.remove(GlyphAttr::BOLD_FAINT);
         }
 
-        Self::new(fg, bg, attr)
+        Self { fg, bg, ul: self.ul, attr }
     }
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct Glyph {
     pub c: char,
+    // id of the zero-width chars attached to c, see ClusterTable
+    pub cluster: u32,
+    // part of an image drawn over the cell
+    pub image: Option<ImageCell>,
     pub prop: GlyphProp,
 }
 
@@ -84,14 +149,19 @@ impl Glyph {
     pub fn new(c: char, fg: usize, bg: usize, attr: GlyphAttr) -> Self {
         Self {
             c,
+            cluster: 0,
+            image: None,
             prop: GlyphProp::new(fg, bg, attr),
         }
     }
 
     pub fn clear(&mut self, cursor: Glyph) {
         self.c = ' ';
+        self.cluster = 0;
+        self.image = None;
         self.prop.fg = cursor.prop.fg;
         self.prop.bg = cursor.prop.bg;
+        self.prop.ul = None;
         self.prop.attr = GlyphAttr::empty();
     }
 }
@@ -0,0 +1,357 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::rc::Rc;
+
+use anyhow::{anyhow, Result};
+
+// max number of pixels of a decoded image, larger images are shrunk to
+// the window when put rather than refused
+pub const IMAGE_PIXELS_MAX: usize = 4096 * 4096;
+
+// Image pixels in RGBA, along with the cells it covers.  The pixels are
+// shared by the clones.
+#[derive(Clone)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Rc<Vec<u8>>,
+    pub cols: usize,
+    pub rows: usize,
+    // image pixels per cell
+    pub cell_width: f64,
+    pub cell_height: f64,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, data: Vec<u8>) -> Self {
+        Image {
+            width,
+            height,
+            data: Rc::new(data),
+            cols: 0,
+            rows: 0,
+            cell_width: 0.0,
+            cell_height: 0.0,
+        }
+    }
+
+    // Cover the cells of cw x ch pixels the image takes unscaled.
+    pub fn at_pixel_size(mut self, cw: usize, ch: usize) -> Self {
+        self.cols = self.width.div_ceil(cw);
+        self.rows = self.height.div_ceil(ch);
+        self.cell_width = cw as f64;
+        self.cell_height = ch as f64;
+        self
+    }
+
+    // Scale the image into cols x rows cells of cw x ch pixels.  A missing
+    // cols or rows is computed from the aspect ratio of the image.  Images
+    // scaled down are shrunk here, so that they are not kept and drawn at
+    // full size.
+    pub fn fit_cells(
+        self,
+        cols: Option<usize>,
+        rows: Option<usize>,
+        keep_aspect: bool,
+        cw: usize,
+        ch: usize,
+    ) -> Self {
+        let (w, h) = (self.width as f64, self.height as f64);
+        let (cw, ch) = (cw as f64, ch as f64);
+
+        let (sx, sy) = match (cols, rows) {
+            (None, None) => (1.0, 1.0),
+            (Some(cols), None) => {
+                let s = cols as f64 * cw / w;
+                (s, s)
+            }
+            (None, Some(rows)) => {
+                let s = rows as f64 * ch / h;
+                (s, s)
+            }
+            (Some(cols), Some(rows)) => {
+                let sx = cols as f64 * cw / w;
+                let sy = rows as f64 * ch / h;
+                if keep_aspect {
+                    let s = sx.min(sy);
+                    (s, s)
+                } else {
+                    (sx, sy)
+                }
+            }
+        };
+
+        let cols = cols.unwrap_or_else(|| (w * sx / cw).ceil() as usize).max(1);
+        let rows = rows.unwrap_or_else(|| (h * sy / ch).ceil() as usize).max(1);
+        let width = ((w * sx).round() as usize).clamp(1, self.width);
+        let height = ((h * sy).round() as usize).clamp(1, self.height);
+        let mut image = if (width, height) == (self.width, self.height) {
+            self
+        } else {
+            self.shrink(width, height)
+        };
+        image.cols = cols;
+        image.rows = rows;
+        image.cell_width = cw * image.width as f64 / (w * sx);
+        image.cell_height = ch * image.height as f64 / (h * sy);
+        image
+    }
+
+    // Shrink the image to width x height pixels, each the average of the
+    // pixels it covers weighted by their alpha.
+    fn shrink(&self, width: usize, height: usize) -> Self {
+        // the source pixels covered by pixel i of n
+        let span = |i: usize, n: usize, size: usize| i * size / n..(i + 1) * size / n;
+
+        let mut data = Vec::with_capacity(width * height * 4);
+        for ys in (0..height).map(|y| span(y, height, self.height)) {
+            for xs in (0..width).map(|x| span(x, width, self.width)) {
+                let mut sum = [0u64; 4];
+                for y in ys.clone() {
+                    let i = y * self.width * 4;
+                    for p in self.data[i + xs.start * 4..i + xs.end * 4].chunks_exact(4) {
+                        let a = p[3] as u64;
+                        sum[0] += p[0] as u64 * a;
+                        sum[1] += p[1] as u64 * a;
+                        sum[2] += p[2] as u64 * a;
+                        sum[3] += a;
+                    }
+                }
+                let n = (xs.len() * ys.len()) as u64;
+                let c = |s: u64| s.checked_div(sum[3]).unwrap_or(0) as u8;
+                data.extend_from_slice(&[c(sum[0]), c(sum[1]), c(sum[2]), (sum[3] / n) as u8]);
+            }
+        }
+        Image::new(width, height, data)
+    }
+
+    // Cut out the w x h rectangle at x, y, a zero size extends to the
+    // right or bottom edge.
+    pub fn crop(self, x: usize, y: usize, w: usize, h: usize) -> Option<Self> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let w = if w == 0 { self.width - x } else { w.min(self.width - x) };
+        let h = if h == 0 { self.height - y } else { h.min(self.height - y) };
+        if (x, y, w, h) == (0, 0, self.width, self.height) {
+            return Some(self);
+        }
+
+        let mut data = Vec::with_capacity(w * h * 4);
+        for row in y..y + h {
+            let i = (row * self.width + x) * 4;
+            data.extend_from_slice(&self.data[i..i + w * 4]);
+        }
+        Some(Image::new(w, h, data))
+    }
+}
+
+// Build an image from packed RGB (bpp 3) or RGBA (bpp 4) pixels.
+pub fn from_raw(width: usize, height: usize, bpp: usize, data: &[u8]) -> Result<Image> {
+    if width == 0 || height == 0 {
+        return Err(anyhow!("no image size"));
+    }
+    if width.saturating_mul(height) > IMAGE_PIXELS_MAX {
+        return Err(anyhow!("image too large {}x{}", width, height));
+    }
+    let size = width * height * bpp;
+    if data.len() < size {
+        return Err(anyhow!("insufficient image data {} < {}", data.len(), size));
+    }
+
+    let data = match bpp {
+        4 => data[..size].to_vec(),
+        3 => data[..size]
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 0xFF])
+            .collect(),
+        _ => return Err(anyhow!("invalid bytes per pixel {}", bpp)),
+    };
+    Ok(Image::new(width, height, data))
+}
+
+// Decode a PNG or JPEG image, told apart by their signatures.
+pub fn decode(data: &[u8]) -> Result<Image> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        from_png(data)
+    } else if data.starts_with(b"\xFF\xD8\xFF") {
+        from_jpeg(data)
+    } else {
+        Err(anyhow!("unsupported image format"))
+    }
+}
+
+pub fn from_png(data: &[u8]) -> Result<Image> {
+    let mut decoder = png::Decoder::new(Cursor::new(data));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let info = reader.info();
+    let (width, height) = (info.width as usize, info.height as usize);
+    if width.saturating_mul(height) > IMAGE_PIXELS_MAX {
+        return Err(anyhow!("image too large {}x{}", width, height));
+    }
+
+    let size = reader
+        .output_buffer_size()
+        .ok_or_else(|| anyhow!("image too large {}x{}", width, height))?;
+    let mut buf = vec![0; size];
+    let frame = reader.next_frame(&mut buf)?;
+    buf.truncate(frame.buffer_size());
+
+    let data = match frame.color_type {
+        png::ColorType::Rgba => buf,
+        png::ColorType::Rgb => buf
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 0xFF])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf
+            .iter()
+            .flat_map(|&v| [v, v, v, 0xFF])
+            .collect(),
+        png::ColorType::Indexed => {
+            return Err(anyhow!("unexpected indexed png output"));
+        }
+    };
+    Ok(Image::new(width, height, data))
+}
+
+pub fn from_jpeg(data: &[u8]) -> Result<Image> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    decoder.read_info()?;
+    let info = decoder.info().ok_or_else(|| anyhow!("no jpeg header"))?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    if width.saturating_mul(height) > IMAGE_PIXELS_MAX {
+        return Err(anyhow!("image too large {}x{}", width, height));
+    }
+
+    let buf = decoder.decode()?;
+    let data = match info.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 => buf
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 0xFF])
+            .collect(),
+        jpeg_decoder::PixelFormat::L8 => buf
+            .iter()
+            .flat_map(|&v| [v, v, v, 0xFF])
+            .collect(),
+        // big endian, the high byte is kept
+        jpeg_decoder::PixelFormat::L16 => buf
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], 0xFF])
+            .collect(),
+        // CMYK is stored inverted by most encoders
+        jpeg_decoder::PixelFormat::CMYK32 => buf
+            .chunks_exact(4)
+            .flat_map(|p| {
+                let k = p[3] as u32;
+                let v = |c: u8| (c as u32 * k / 255) as u8;
+                [v(p[0]), v(p[1]), v(p[2]), 0xFF]
+            })
+            .collect(),
+    };
+    Ok(Image::new(width, height, data))
+}
+
+// The part of an image drawn in a cell.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageCell {
+    pub id: u32,
+    pub col: u16,
+    pub row: u16,
+}
+
+// Images referred to by cells.
+pub struct ImageTable {
+    images: HashMap<u32, Image>,
+    next_id: u32,
+    // ids dropped since the last take_freed()
+    freed: Vec<u32>,
+}
+
+impl ImageTable {
+    pub fn new() -> Self {
+        ImageTable {
+            images: HashMap::new(),
+            next_id: 1,
+            freed: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, image: Image) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        self.images.insert(id, image);
+        id
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Image> {
+        self.images.get(&id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    // Drop the images not in used.
+    pub fn retain(&mut self, used: &HashSet<u32>) {
+        let freed = &mut self.freed;
+        self.images.retain(|id, _| {
+            let keep = used.contains(id);
+            if !keep {
+                freed.push(*id);
+            }
+            keep
+        });
+    }
+
+    pub fn take_freed(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.freed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an image of w x h opaque pixels, the red of each is its index
+    fn image(w: usize, h: usize) -> Image {
+        let data = (0..w * h).flat_map(|i| [i as u8, 0, 0, 0xFF]).collect();
+        Image::new(w, h, data)
+    }
+
+    #[test]
+    fn fit_cells_shrinks_once() {
+        let image = image(4, 4).fit_cells(Some(1), None, true, 2, 2);
+        assert_eq!((image.width, image.height), (2, 2));
+        assert_eq!((image.cols, image.rows), (1, 1));
+        assert_eq!((image.cell_width, image.cell_height), (2.0, 2.0));
+        // the top left pixel is the average of 0, 1, 4 and 5
+        assert_eq!(image.data[..4], [2, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn fit_cells_keeps_pixels_when_enlarging() {
+        let src = image(2, 2);
+        let image = src.clone().fit_cells(Some(2), Some(2), false, 2, 4);
+        assert!(Rc::ptr_eq(&image.data, &src.data));
+        assert_eq!((image.cell_width, image.cell_height), (1.0, 1.0));
+    }
+
+    #[test]
+    fn shrink_weighs_by_alpha() {
+        let data = vec![255, 0, 0, 0xFF, 0, 255, 0, 0];
+        let image = Image::new(2, 1, data).fit_cells(Some(1), Some(1), false, 1, 1);
+        assert_eq!(image.data[..], [255, 0, 0, 0x7F]);
+    }
+
+    #[test]
+    fn full_hd_images_are_decoded() {
+        let data = vec![0; 1920 * 1080 * 3];
+        assert!(from_raw(1920, 1080, 3, &data).is_ok());
+        assert!(from_raw(4097, 4096, 3, &data).is_err());
+    }
+}
@@ -2,6 +2,7 @@ use crate::win::WinMode;
 
 use std::os::raw::*;
 
+use bitflags::bitflags;
 use x11::keysym::*;
 use x11::xlib::*;
 
@@ -296,3 +297,372 @@ pub fn map_key(k: KeySym, state: c_uint, mode: &WinMode) -> Option<&'static [u8]
 
     None
 }
+
+// Key numbers of DECUDK, they match the codes of the function keys in
+// "CSI n ~".
+pub fn udk_key(k: KeySym) -> Option<u16> {
+    let n = match k as c_uint {
+        XK_F1..=XK_F5 => 11 + k as c_uint - XK_F1,
+        XK_F6..=XK_F10 => 17 + k as c_uint - XK_F6,
+        XK_F11..=XK_F14 => 23 + k as c_uint - XK_F11,
+        XK_F15..=XK_F16 => 28 + k as c_uint - XK_F15,
+        XK_F17..=XK_F20 => 31 + k as c_uint - XK_F17,
+        _ => return None,
+    };
+    Some(n as u16)
+}
+
+bitflags! {
+    // kitty keyboard protocol progressive enhancements
+    #[derive(Clone, Copy, PartialEq)]
+    pub struct KeyFlags: u16 {
+        const DISAMBIGUATE    = 1 << 0;
+        const EVENT_TYPES     = 1 << 1;
+        const ALTERNATE_KEYS  = 1 << 2;
+        const ALL_KEYS        = 1 << 3;
+        const ASSOCIATED_TEXT = 1 << 4;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyEvent {
+    Press = 1,
+    Repeat = 2,
+    Release = 3,
+}
+
+// modifier bits of kitty and xterm, the value sent is one more than them
+const MOD_SHIFT: u32 = 1;
+const MOD_ALT: u32 = 2;
+const MOD_CTRL: u32 = 4;
+const MOD_SUPER: u32 = 8;
+const MOD_CAPS_LOCK: u32 = 64;
+const MOD_NUM_LOCK: u32 = 128;
+
+fn modifiers(state: c_uint) -> u32 {
+    let mut mods = 0;
+    for (mask, bit) in [
+        (ShiftMask, MOD_SHIFT),
+        (Mod1Mask, MOD_ALT),
+        (ControlMask, MOD_CTRL),
+        (Mod4Mask, MOD_SUPER),
+    ] {
+        if state & mask != 0 {
+            mods |= bit;
+        }
+    }
+    mods
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum KeyKind {
+    Text,
+    // enter, tab and backspace
+    Control,
+    Escape,
+    Keypad,
+    Modifier,
+    Function,
+}
+
+// Kitty key number and final byte of functional keys.
+#[allow(non_upper_case_globals)]
+fn functional_key(k: c_uint) -> Option<(u32, u8, KeyKind)> {
+    use KeyKind::*;
+    let key = match k {
+        XK_Escape => (27, b'u', Escape),
+        XK_Return => (13, b'u', Control),
+        XK_Tab | XK_ISO_Left_Tab => (9, b'u', Control),
+        XK_BackSpace => (127, b'u', Control),
+        XK_Insert => (2, b'~', Function),
+        XK_Delete => (3, b'~', Function),
+        XK_Left => (1, b'D', Function),
+        XK_Right => (1, b'C', Function),
+        XK_Up => (1, b'A', Function),
+        XK_Down => (1, b'B', Function),
+        XK_Prior => (5, b'~', Function),
+        XK_Next => (6, b'~', Function),
+        XK_Home => (1, b'H', Function),
+        XK_End => (1, b'F', Function),
+        XK_Caps_Lock => (57358, b'u', Function),
+        XK_Scroll_Lock => (57359, b'u', Function),
+        XK_Num_Lock => (57360, b'u', Function),
+        XK_Print => (57361, b'u', Function),
+        XK_Pause => (57362, b'u', Function),
+        XK_Menu => (57363, b'u', Function),
+        XK_F1 => (1, b'P', Function),
+        XK_F2 => (1, b'Q', Function),
+        XK_F3 => (13, b'~', Function),
+        XK_F4 => (1, b'S', Function),
+        XK_F5..=XK_F12 => (udk_key(k as KeySym)? as u32, b'~', Function),
+        XK_F13..=XK_F35 => (57376 + k - XK_F13, b'u', Function),
+        XK_KP_0..=XK_KP_9 => (57399 + k - XK_KP_0, b'u', Keypad),
+        XK_KP_Decimal => (57409, b'u', Keypad),
+        XK_KP_Divide => (57410, b'u', Keypad),
+        XK_KP_Multiply => (57411, b'u', Keypad),
+        XK_KP_Subtract => (57412, b'u', Keypad),
+        XK_KP_Add => (57413, b'u', Keypad),
+        XK_KP_Enter => (57414, b'u', Keypad),
+        XK_KP_Equal => (57415, b'u', Keypad),
+        XK_KP_Separator => (57416, b'u', Keypad),
+        XK_KP_Left => (57417, b'u', Keypad),
+        XK_KP_Right => (57418, b'u', Keypad),
+        XK_KP_Up => (57419, b'u', Keypad),
+        XK_KP_Down => (57420, b'u', Keypad),
+        XK_KP_Prior => (57421, b'u', Keypad),
+        XK_KP_Next => (57422, b'u', Keypad),
+        XK_KP_Home => (57423, b'u', Keypad),
+        XK_KP_End => (57424, b'u', Keypad),
+        XK_KP_Insert => (57425, b'u', Keypad),
+        XK_KP_Delete => (57426, b'u', Keypad),
+        XK_KP_Begin => (57427, b'u', Keypad),
+        XK_Shift_L => (57441, b'u', Modifier),
+        XK_Control_L => (57442, b'u', Modifier),
+        XK_Alt_L => (57443, b'u', Modifier),
+        XK_Super_L => (57444, b'u', Modifier),
+        XK_Hyper_L => (57445, b'u', Modifier),
+        XK_Meta_L => (57446, b'u', Modifier),
+        XK_Shift_R => (57447, b'u', Modifier),
+        XK_Control_R => (57448, b'u', Modifier),
+        XK_Alt_R => (57449, b'u', Modifier),
+        XK_Super_R => (57450, b'u', Modifier),
+        XK_Hyper_R => (57451, b'u', Modifier),
+        XK_Meta_R => (57452, b'u', Modifier),
+        XK_ISO_Level3_Shift => (57453, b'u', Modifier),
+        XK_ISO_Level5_Shift => (57454, b'u', Modifier),
+        _ => return None,
+    };
+    Some(key)
+}
+
+pub fn keysym_char(k: KeySym) -> Option<char> {
+    match k as u32 {
+        k @ (0x20..=0x7E | 0xA0..=0xFF) => char::from_u32(k),
+        // unicode keysyms
+        k @ 0x0100_0100..=0x0110_FFFF => char::from_u32(k - 0x0100_0000),
+        _ => None,
+    }
+}
+
+// Encode a key event with the kitty keyboard protocol.  ksym is the
+// looked up keysym, base and shifted are the keysyms of the key without
+// and with shift, text is the text it types.  An empty sequence means the
+// event is not reported, None means the legacy encoding is used.
+#[allow(clippy::too_many_arguments)]
+pub fn kitty_key(
+    ksym: KeySym,
+    base: KeySym,
+    shifted: KeySym,
+    state: c_uint,
+    event: KeyEvent,
+    text: &str,
+    flags: KeyFlags,
+) -> Option<Vec<u8>> {
+    let (code, end, kind) = match functional_key(ksym as c_uint) {
+        Some(key) => key,
+        None => {
+            let c = keysym_char(base).or_else(|| text.chars().next())?;
+            (c.to_lowercase().next()? as u32, b'u', KeyKind::Text)
+        }
+    };
+
+    // lock modifiers are only reported with ALL_KEYS
+    let all = flags.contains(KeyFlags::ALL_KEYS);
+    let mut mods = modifiers(state);
+    if all && state & LockMask != 0 {
+        mods |= MOD_CAPS_LOCK;
+    }
+    if all && state & Mod2Mask != 0 {
+        mods |= MOD_NUM_LOCK;
+    }
+
+    // text without control chars is typed as is
+    let text = if text.chars().any(|c| c.is_control()) { "" } else { text };
+    let typed = !text.is_empty() && mods & !MOD_SHIFT == 0;
+    let disambiguate = flags.contains(KeyFlags::DISAMBIGUATE);
+    let legacy = !all && match kind {
+        KeyKind::Text => typed || !disambiguate,
+        KeyKind::Control | KeyKind::Function => mods == 0 || !disambiguate,
+        KeyKind::Escape => !disambiguate,
+        KeyKind::Keypad => typed || !disambiguate,
+        KeyKind::Modifier => true,
+    };
+    // enter, tab, backspace and modifiers are only released with ALL_KEYS,
+    // function keys are only repeated with the legacy encoding when not
+    // reporting event types
+    let event_types = flags.contains(KeyFlags::EVENT_TYPES);
+    match event {
+        KeyEvent::Release
+            if !event_types || (legacy && matches!(kind, KeyKind::Control | KeyKind::Modifier)) =>
+        {
+            return Some(Vec::new());
+        }
+        KeyEvent::Press if legacy => return None,
+        KeyEvent::Repeat if legacy && (!event_types || kind != KeyKind::Function) => {
+            return None;
+        }
+        _ => (),
+    }
+
+    let mut key = code.to_string();
+    let alternate = flags.contains(KeyFlags::ALTERNATE_KEYS);
+    if alternate && kind == KeyKind::Text && mods & MOD_SHIFT != 0 {
+        if let Some(c) = keysym_char(shifted).filter(|&c| c as u32 != code) {
+            key += &format!(":{}", c as u32);
+        }
+    }
+
+    let event = if event_types { event } else { KeyEvent::Press };
+    let mut modifiers = String::new();
+    if mods != 0 || event != KeyEvent::Press {
+        modifiers = (mods + 1).to_string();
+    }
+    if event != KeyEvent::Press {
+        modifiers += &format!(":{}", event as u32);
+    }
+
+    let mut fields = vec![key, modifiers];
+    let associated = flags.contains(KeyFlags::ASSOCIATED_TEXT) && all;
+    if associated && !text.is_empty() && event != KeyEvent::Release {
+        let codes: Vec<String> = text.chars().map(|c| (c as u32).to_string()).collect();
+        fields.push(codes.join(":"));
+    }
+    while fields.last().is_some_and(|f| f.is_empty()) {
+        fields.pop();
+    }
+    // the key number 1 is left out of CSI A and the like
+    if end != b'u' && end != b'~' && fields.len() == 1 {
+        fields.clear();
+    }
+
+    Some(format!("\x1b[{}{}", fields.join(";"), end as char).into_bytes())
+}
+
+// Encode a modified key with xterm modifyOtherKeys.  Level 1 only changes
+// the keys the legacy encoding loses, level 2 all modified keys but the
+// shifted printable ones.  format is xterm formatOtherKeys, 0 for
+// "CSI 27;mod;code~" and 1 for "CSI code;mod u".  None means the legacy
+// encoding is used.
+#[allow(non_upper_case_globals)]
+pub fn modify_other_key(
+    k: KeySym, state: c_uint, text: &[u8], level: u8, format: u8
+) -> Option<Vec<u8>> {
+    let (code, special) = match k as c_uint {
+        XK_Return => (13, true),
+        XK_Tab | XK_ISO_Left_Tab => (9, true),
+        XK_BackSpace => (127, true),
+        XK_Escape => (27, true),
+        _ => (keysym_char(k)? as u32, false),
+    };
+
+    let mods = modifiers(state);
+    let modify = match level {
+        // ctrl keys with no control char of their own, or with shift
+        1 => {
+            let control = matches!(text, [c] if *c < 0x20 || *c == 0x7F);
+            !special && mods & MOD_CTRL != 0 && (!control || mods & MOD_SHIFT != 0)
+        }
+        2 => mods & !MOD_SHIFT != 0 || (special && mods != 0),
+        _ => false,
+    };
+    if !modify {
+        return None;
+    }
+
+    let s = match format {
+        0 => format!("\x1b[27;{};{}~", mods + 1, code),
+        _ => format!("\x1b[{};{}u", code, mods + 1),
+    };
+    Some(s.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(non_upper_case_globals)]
+    fn kitty(ksym: u32, state: c_uint, event: KeyEvent, text: &str, flags: KeyFlags) -> String {
+        let shifted = match ksym {
+            XK_a => XK_A,
+            XK_1 => XK_exclam,
+            k => k,
+        };
+        let (k, shifted) = (ksym as KeySym, shifted as KeySym);
+        let s = kitty_key(k, k, shifted, state, event, text, flags);
+        String::from_utf8(s.expect("legacy encoding")).unwrap()
+    }
+
+    fn is_legacy(ksym: u32, state: c_uint, event: KeyEvent, text: &str, flags: KeyFlags) -> bool {
+        let k = ksym as KeySym;
+        kitty_key(k, k, k, state, event, text, flags).is_none()
+    }
+
+    #[test]
+    fn kitty_disambiguate() {
+        let flags = KeyFlags::DISAMBIGUATE;
+        let press = KeyEvent::Press;
+        // plain text and unmodified enter keep the legacy encoding
+        assert!(is_legacy(XK_a, 0, press, "a", flags));
+        assert!(is_legacy(XK_Return, 0, press, "\r", flags));
+        assert_eq!(kitty(XK_a, ControlMask, press, "\x01", flags), "\x1b[97;5u");
+        assert_eq!(kitty(XK_Escape, 0, press, "\x1b", flags), "\x1b[27u");
+        assert_eq!(kitty(XK_Return, Mod1Mask, press, "\r", flags), "\x1b[13;3u");
+        assert_eq!(kitty(XK_Up, ControlMask, press, "", flags), "\x1b[1;5A");
+        assert_eq!(kitty(XK_F5, ShiftMask, press, "", flags), "\x1b[15;2~");
+        // releases are not reported without EVENT_TYPES
+        assert_eq!(kitty(XK_Escape, 0, KeyEvent::Release, "", flags), "");
+    }
+
+    #[test]
+    fn kitty_event_types() {
+        let flags = KeyFlags::DISAMBIGUATE | KeyFlags::EVENT_TYPES;
+        assert_eq!(kitty(XK_a, ControlMask, KeyEvent::Repeat, "", flags), "\x1b[97;5:2u");
+        assert_eq!(kitty(XK_Up, 0, KeyEvent::Release, "", flags), "\x1b[1;1:3A");
+        assert_eq!(kitty(XK_Up, 0, KeyEvent::Repeat, "", flags), "\x1b[1;1:2A");
+        // enter is only released with ALL_KEYS
+        assert_eq!(kitty(XK_Return, 0, KeyEvent::Release, "", flags), "");
+    }
+
+    #[test]
+    fn kitty_all_keys() {
+        let flags = KeyFlags::ALL_KEYS | KeyFlags::ALTERNATE_KEYS | KeyFlags::ASSOCIATED_TEXT;
+        let press = KeyEvent::Press;
+        assert_eq!(kitty(XK_a, 0, press, "a", flags), "\x1b[97;;97u");
+        assert_eq!(kitty(XK_a, ShiftMask, press, "A", flags), "\x1b[97:65;2;65u");
+        assert_eq!(kitty(XK_1, ShiftMask, press, "!", flags), "\x1b[49:33;2;33u");
+        assert_eq!(kitty(XK_a, LockMask, press, "A", flags), "\x1b[97;65;65u");
+        assert_eq!(kitty(XK_Shift_L, 0, press, "", flags), "\x1b[57441u");
+        assert_eq!(kitty(XK_KP_1, Mod2Mask, press, "1", flags), "\x1b[57400;129;49u");
+        assert_eq!(kitty(XK_Return, 0, press, "\r", flags), "\x1b[13u");
+    }
+
+    fn other_key(ksym: u32, state: c_uint, text: &[u8], level: u8, format: u8) -> Option<String> {
+        let s = modify_other_key(ksym as KeySym, state, text, level, format)?;
+        Some(String::from_utf8(s).unwrap())
+    }
+
+    #[test]
+    fn modify_other_keys_level_1() {
+        // ctrl keys with a control char of their own are left alone
+        assert_eq!(other_key(XK_a, ControlMask, b"\x01", 1, 0), None);
+        assert_eq!(other_key(XK_Return, ControlMask, b"\r", 1, 0), None);
+        assert_eq!(other_key(XK_1, ControlMask, b"1", 1, 0).unwrap(), "\x1b[27;5;49~");
+        let (state, text) = (ControlMask | ShiftMask, b"\x01");
+        assert_eq!(other_key(XK_A, state, text, 1, 0).unwrap(), "\x1b[27;6;65~");
+    }
+
+    #[test]
+    fn modify_other_keys_level_2() {
+        assert_eq!(other_key(XK_a, 0, b"a", 2, 0), None);
+        assert_eq!(other_key(XK_A, ShiftMask, b"A", 2, 0), None);
+        assert_eq!(other_key(XK_a, ControlMask, b"\x01", 2, 0).unwrap(), "\x1b[27;5;97~");
+        assert_eq!(other_key(XK_Tab, ShiftMask, b"\t", 2, 0).unwrap(), "\x1b[27;2;9~");
+        assert_eq!(other_key(XK_a, ControlMask, b"\x01", 0, 0), None);
+    }
+
+    #[test]
+    fn format_other_keys() {
+        assert_eq!(other_key(XK_a, Mod1Mask, b"a", 2, 1).unwrap(), "\x1b[97;3u");
+        assert_eq!(other_key(XK_Escape, ControlMask, b"\x1b", 2, 1).unwrap(), "\x1b[27;5u");
+    }
+}
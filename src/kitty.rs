@@ -0,0 +1,500 @@
+// kitty graphics protocol reference:
+// - https://sw.kovidgoyal.net/kitty/graphics-protocol/
+
+use crate::config::FilePolicy;
+use crate::image::{self, Image};
+use crate::term::{ImageCursor, Term};
+use crate::utils::base64_decode;
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+// max size of the data of one image, as transmitted
+const DATA_SIZE_MAX: usize = 128 * 1024 * 1024;
+// max size of the decoded images kept, the oldest are dropped first
+const STORAGE_MAX: usize = 320 * 1024 * 1024;
+// temp files are only read and removed with this in their name
+const TEMP_FILE_TAG: &str = "tty-graphics-protocol";
+
+// Control data of a graphics command, keys are documented at their
+// fields.
+struct Command {
+    // a: t transmit, T transmit and put, p put, d delete, q query
+    action: u8,
+    // q: 1 suppresses OK replies, 2 suppresses errors as well
+    quiet: u32,
+    // f: 24 RGB, 32 RGBA or 100 PNG
+    format: u32,
+    // t: d direct, f file, t temp file
+    medium: u8,
+    // o: z for zlib deflate
+    compression: u8,
+    // s, v: pixel size of RGB and RGBA data
+    width: usize,
+    height: usize,
+    // O, S: part of the file to read
+    offset: usize,
+    size: usize,
+    // i, I, p: image id, image number and placement id
+    id: u32,
+    number: u32,
+    placement: u32,
+    // m: more chunks follow
+    more: bool,
+    // x, y, w, h: source rectangle, or the cell to delete at
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+    // c, r: cells to scale the image into
+    cols: usize,
+    rows: usize,
+    // C: do not move the cursor
+    stay: bool,
+    // U: unicode placeholders
+    unicode: bool,
+    // d: what to delete, uppercase also frees the image data
+    delete: u8,
+}
+
+impl Command {
+    fn parse(control: &[u8]) -> Self {
+        let mut cmd = Command {
+            action: b't',
+            quiet: 0,
+            format: 32,
+            medium: b'd',
+            compression: 0,
+            width: 0,
+            height: 0,
+            offset: 0,
+            size: 0,
+            id: 0,
+            number: 0,
+            placement: 0,
+            more: false,
+            x: 0,
+            y: 0,
+            w: 0,
+            h: 0,
+            cols: 0,
+            rows: 0,
+            stay: false,
+            unicode: false,
+            delete: b'a',
+        };
+
+        for item in control.split(|&b| b == b',') {
+            let (key, value) = match item {
+                [key, b'=', value @ ..] if !value.is_empty() => (*key, value),
+                _ => continue,
+            };
+            let n = std::str::from_utf8(value)
+                .ok()
+                .and_then(|s| s.parse::<u32>().ok())
+                .unwrap_or(0);
+            match key {
+                b'a' => cmd.action = value[0],
+                b'q' => cmd.quiet = n,
+                b'f' => cmd.format = n,
+                b't' => cmd.medium = value[0],
+                b'o' => cmd.compression = value[0],
+                b's' => cmd.width = n as usize,
+                b'v' => cmd.height = n as usize,
+                b'O' => cmd.offset = n as usize,
+                b'S' => cmd.size = n as usize,
+                b'i' => cmd.id = n,
+                b'I' => cmd.number = n,
+                b'p' => cmd.placement = n,
+                b'm' => cmd.more = n == 1,
+                b'x' => cmd.x = n as usize,
+                b'y' => cmd.y = n as usize,
+                b'w' => cmd.w = n as usize,
+                b'h' => cmd.h = n as usize,
+                b'c' => cmd.cols = n.min(u16::MAX as u32) as usize,
+                b'r' => cmd.rows = n.min(u16::MAX as u32) as usize,
+                b'C' => cmd.stay = n == 1,
+                b'U' => cmd.unicode = n == 1,
+                b'd' => cmd.delete = value[0],
+                _ => (),
+            }
+        }
+        cmd
+    }
+}
+
+struct StoredImage {
+    image: Image,
+    number: u32,
+    // order of transmission
+    seq: u64,
+}
+
+// Images transmitted by the application.  Their pixels are shared with the
+// term when put, so that placements scroll and are cleared like text.
+pub struct Kitty {
+    images: HashMap<u32, StoredImage>,
+    next_id: u32,
+    seq: u64,
+    // term image id to image id and placement id
+    placements: HashMap<u32, (u32, u32)>,
+    // chunked transmission being received
+    pending: Option<(Command, Vec<u8>)>,
+    files: FilePolicy,
+}
+
+impl Kitty {
+    pub fn new(files: FilePolicy) -> Self {
+        Kitty {
+            images: HashMap::new(),
+            next_id: 1,
+            seq: 0,
+            placements: HashMap::new(),
+            pending: None,
+            files,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Kitty::new(self.files);
+    }
+
+    // Handle the payload of an APC G string, return the reply if any.
+    pub fn command(
+        &mut self, payload: &[u8], term: &mut Term, cw: usize, ch: usize
+    ) -> Option<Vec<u8>> {
+        let (control, data) = match payload.iter().position(|&b| b == b';') {
+            Some(i) => (&payload[..i], &payload[i + 1..]),
+            None => (payload, &[][..]),
+        };
+        let cmd = Command::parse(control);
+
+        // only m is taken from the control data of following chunks
+        if let Some((first, mut buf)) = self.pending.take() {
+            if buf.len() + data.len() > DATA_SIZE_MAX {
+                return reply(&first, Err(anyhow!("EFBIG:image data too large")));
+            }
+            buf.extend_from_slice(data);
+            if cmd.more {
+                self.pending = Some((first, buf));
+                return None;
+            }
+            return self.transmit(first, &buf, term, cw, ch);
+        }
+
+        match cmd.action {
+            b't' | b'T' | b'q' if cmd.more => {
+                self.pending = Some((cmd, data.to_vec()));
+                None
+            }
+            b't' | b'T' | b'q' => self.transmit(cmd, data, term, cw, ch),
+            b'p' => {
+                let result = self
+                    .find(&cmd)
+                    .and_then(|id| self.place(id, &cmd, term, cw, ch));
+                reply(&cmd, result)
+            }
+            b'd' => {
+                self.delete(&cmd, term);
+                None
+            }
+            _ => reply(
+                &cmd,
+                Err(anyhow!("EINVAL:unsupported action {}", cmd.action as char)),
+            ),
+        }
+    }
+
+    fn transmit(
+        &mut self, mut cmd: Command, data: &[u8], term: &mut Term, cw: usize, ch: usize
+    ) -> Option<Vec<u8>> {
+        let image = match load(&cmd, data, self.files) {
+            Ok(image) => image,
+            Err(e) => return reply(&cmd, Err(e)),
+        };
+        if cmd.action == b'q' {
+            return reply(&cmd, Ok(()));
+        }
+
+        let id = if cmd.id > 0 { cmd.id } else { self.new_id() };
+        if cmd.number > 0 {
+            cmd.id = id;
+        }
+        // an image replaced by a new one loses its placements
+        self.clear_placements(term, |_, (i, _)| i == id);
+        self.store(id, cmd.number, image);
+
+        let result = if cmd.action == b'T' {
+            self.place(id, &cmd, term, cw, ch)
+        } else {
+            Ok(())
+        };
+        reply(&cmd, result)
+    }
+
+    fn new_id(&mut self) -> u32 {
+        while self.images.contains_key(&self.next_id) {
+            self.next_id = self.next_id.wrapping_add(1).max(1);
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        id
+    }
+
+    fn store(&mut self, id: u32, number: u32, image: Image) {
+        self.seq += 1;
+        self.images.insert(id, StoredImage { image, number, seq: self.seq });
+
+        let mut total: usize = self.images.values().map(|s| s.image.data.len()).sum();
+        while total > STORAGE_MAX {
+            let (&oldest, _) = self.images.iter().min_by_key(|(_, s)| s.seq).unwrap();
+            if oldest == id {
+                break;
+            }
+            total -= self.images.remove(&oldest).unwrap().image.data.len();
+        }
+    }
+
+    // Get the image referred to by id or, failing that, number.  The
+    // newest image is taken when several have the same number.
+    fn find(&self, cmd: &Command) -> Result<u32> {
+        let id = if cmd.id > 0 {
+            Some(cmd.id).filter(|id| self.images.contains_key(id))
+        } else if cmd.number > 0 {
+            self.images
+                .iter()
+                .filter(|(_, s)| s.number == cmd.number)
+                .max_by_key(|(_, s)| s.seq)
+                .map(|(&id, _)| id)
+        } else {
+            None
+        };
+        id.ok_or_else(|| anyhow!("ENOENT:image not found"))
+    }
+
+    fn place(
+        &mut self, id: u32, cmd: &Command, term: &mut Term, cw: usize, ch: usize
+    ) -> Result<()> {
+        if cmd.unicode {
+            return Err(anyhow!("EINVAL:unicode placeholders not supported"));
+        }
+        let image = self
+            .images
+            .get(&id)
+            .ok_or_else(|| anyhow!("ENOENT:image not found"))?
+            .image
+            .clone()
+            .crop(cmd.x, cmd.y, cmd.w, cmd.h)
+            .ok_or_else(|| anyhow!("EINVAL:source rectangle out of the image"))?;
+        let mut cols = Some(cmd.cols).filter(|&n| n > 0);
+        let rows = Some(cmd.rows).filter(|&n| n > 0);
+        // images are shrunk to the screen width unless sized
+        if cols.is_none() && rows.is_none() && image.width > term.cols * cw {
+            cols = Some(term.cols);
+        }
+        let image = image.fit_cells(cols, rows, false, cw, ch);
+
+        if cmd.placement > 0 {
+            let pid = cmd.placement;
+            self.clear_placements(term, |_, p| p == (id, pid));
+        }
+        let cursor = if cmd.stay {
+            ImageCursor::Stay
+        } else {
+            ImageCursor::AfterImage
+        };
+        let tid = term.put_image(image, cursor);
+        self.placements.retain(|&tid, _| term.get_image(tid).is_some());
+        self.placements.insert(tid, (id, cmd.placement));
+        Ok(())
+    }
+
+    fn delete(&mut self, cmd: &Command, term: &mut Term) {
+        // cells are 1-based
+        let (x, y) = (cmd.x.saturating_sub(1), cmd.y.saturating_sub(1));
+        let cells: Vec<(usize, usize)> = match cmd.delete.to_ascii_lowercase() {
+            b'a' => (0..term.rows)
+                .flat_map(|y| (0..term.cols).map(move |x| (x, y)))
+                .collect(),
+            b'c' => vec![term.cursor_cell()],
+            b'p' if x < term.cols && y < term.rows => vec![(x, y)],
+            b'x' if x < term.cols => (0..term.rows).map(|y| (x, y)).collect(),
+            b'y' if y < term.rows => (0..term.cols).map(|x| (x, y)).collect(),
+            b'p' | b'x' | b'y' => Vec::new(),
+            b'i' | b'n' => Vec::new(),
+            _ => {
+                println!("unsupported kitty delete {}", cmd.delete as char);
+                return;
+            }
+        };
+
+        let mut ids = match cmd.delete.to_ascii_lowercase() {
+            b'i' if cmd.id > 0 => {
+                let (id, pid) = (cmd.id, cmd.placement);
+                self.clear_placements(term, |_, p| p.0 == id && (pid == 0 || p.1 == pid));
+                HashSet::from([id])
+            }
+            b'n' => match self.find(cmd) {
+                Ok(id) => {
+                    let pid = cmd.placement;
+                    self.clear_placements(term, |_, p| p.0 == id && (pid == 0 || p.1 == pid));
+                    HashSet::from([id])
+                }
+                Err(_) => HashSet::new(),
+            },
+            _ => {
+                let tids: HashSet<u32> = cells
+                    .into_iter()
+                    .filter_map(|(x, y)| term.image_at(x, y))
+                    .map(|cell| cell.id)
+                    .collect();
+                self.clear_placements(term, |tid, _| tids.contains(&tid))
+            }
+        };
+
+        // uppercase frees the data of the images left with no placement
+        if cmd.delete.is_ascii_uppercase() {
+            ids.retain(|id| !self.placements.values().any(|p| p.0 == *id));
+            self.images.retain(|id, _| !ids.contains(id));
+        }
+    }
+
+    // Remove the placements matching f from the term, return their image
+    // ids.
+    fn clear_placements<F>(&mut self, term: &mut Term, f: F) -> HashSet<u32>
+    where
+        F: Fn(u32, (u32, u32)) -> bool,
+    {
+        self.placements.retain(|&tid, _| term.get_image(tid).is_some());
+        let tids: HashSet<u32> = self
+            .placements
+            .iter()
+            .filter(|(&tid, &p)| f(tid, p))
+            .map(|(&tid, _)| tid)
+            .collect();
+        let ids = tids.iter().map(|tid| self.placements[tid].0).collect();
+
+        term.clear_images(&tids);
+        self.placements.retain(|tid, _| !tids.contains(tid));
+        ids
+    }
+}
+
+fn reply(cmd: &Command, result: Result<()>) -> Option<Vec<u8>> {
+    if cmd.id == 0 && cmd.number == 0 {
+        return None;
+    }
+    let msg = match result {
+        Ok(()) if cmd.quiet == 0 => "OK".to_string(),
+        Err(e) if cmd.quiet < 2 => e.to_string(),
+        _ => return None,
+    };
+
+    let mut keys = vec![format!("i={}", cmd.id)];
+    if cmd.number > 0 {
+        keys.push(format!("I={}", cmd.number));
+    }
+    if cmd.placement > 0 {
+        keys.push(format!("p={}", cmd.placement));
+    }
+    Some(format!("\x1b_G{};{}\x1b\\", keys.join(","), msg).into_bytes())
+}
+
+fn load(cmd: &Command, data: &[u8], files: FilePolicy) -> Result<Image> {
+    let data = base64_decode(data).map_err(|e| anyhow!("EINVAL:{}", e))?;
+    // files are refused before looking at them, so that the replies tell
+    // nothing about them
+    let data = match cmd.medium {
+        b'd' => data,
+        b'f' if files < FilePolicy::Allow => {
+            return Err(anyhow!("EPERM:file transmission not allowed"));
+        }
+        b't' if files < FilePolicy::Temp => {
+            return Err(anyhow!("EPERM:file transmission not allowed"));
+        }
+        b'f' | b't' => read_file(&data, cmd.offset, cmd.size, cmd.medium == b't')?,
+        _ => return Err(anyhow!("EINVAL:unsupported transmission medium")),
+    };
+    let data = match cmd.compression {
+        0 => data,
+        b'z' => miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&data, DATA_SIZE_MAX)
+            .map_err(|e| anyhow!("EINVAL:inflate failed {:?}", e.status))?,
+        _ => return Err(anyhow!("EINVAL:unsupported compression")),
+    };
+
+    match cmd.format {
+        24 | 32 => {
+            let bpp = cmd.format as usize / 8;
+            image::from_raw(cmd.width, cmd.height, bpp, &data)
+                .map_err(|e| anyhow!("ENODATA:{}", e))
+        }
+        100 => image::from_png(&data).map_err(|e| anyhow!("EBADPNG:{}", e)),
+        _ => Err(anyhow!("EINVAL:unsupported format {}", cmd.format)),
+    }
+}
+
+// Read size bytes at offset of a regular file, a size of 0 reads to the
+// end.  Temp files are removed after reading.
+fn read_file(path: &[u8], offset: usize, size: usize, temp: bool) -> Result<Vec<u8>> {
+    let path = Path::new(OsStr::from_bytes(path));
+    let path = fs::canonicalize(path).map_err(|e| anyhow!("EBADF:{}", e))?;
+    if ["/proc", "/sys"].iter().any(|dir| path.starts_with(dir)) {
+        return Err(anyhow!("EPERM:not a regular file"));
+    }
+    if temp && !is_temp_file(&path) {
+        return Err(anyhow!("EPERM:not a graphics temp file"));
+    }
+
+    let mut file = File::open(&path).map_err(|e| anyhow!("EBADF:{}", e))?;
+    if !file.metadata()?.is_file() {
+        return Err(anyhow!("EPERM:not a regular file"));
+    }
+    let limit = if size == 0 { DATA_SIZE_MAX } else { size.min(DATA_SIZE_MAX) };
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(offset as u64))
+        .and_then(|_| file.take(limit as u64).read_to_end(&mut data))
+        .map_err(|e| anyhow!("EBADF:{}", e))?;
+
+    if temp {
+        let _ = fs::remove_file(&path);
+    }
+    Ok(data)
+}
+
+fn is_temp_file(path: &Path) -> bool {
+    let dirs = [env::temp_dir(), PathBuf::from("/tmp"), PathBuf::from("/dev/shm")];
+    let in_temp = dirs
+        .iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .any(|dir| path.starts_with(dir));
+    in_temp && path.to_string_lossy().contains(TEMP_FILE_TAG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_error(control: &[u8], path: &str, files: FilePolicy) -> String {
+        let cmd = Command::parse(control);
+        let path = crate::utils::base64_encode(path.as_bytes());
+        load(&cmd, path.as_bytes(), files).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn file_policy() {
+        let path = "/nonexistent/tty-graphics-protocol.png";
+        let denied = "EPERM:file transmission not allowed";
+        assert_eq!(load_error(b"t=f", path, FilePolicy::Deny), denied);
+        assert_eq!(load_error(b"t=t", path, FilePolicy::Deny), denied);
+        assert_eq!(load_error(b"t=f", path, FilePolicy::Temp), denied);
+        assert!(load_error(b"t=t", path, FilePolicy::Temp).starts_with("EBADF:"));
+        assert!(load_error(b"t=f", path, FilePolicy::Allow).starts_with("EBADF:"));
+    }
+}
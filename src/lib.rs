@@ -1,13 +1,17 @@
 mod charset;
+mod cluster;
 mod color;
 mod cursor;
 mod font;
 mod glyph;
+mod image;
 mod keymap;
+mod kitty;
 mod point;
 mod pty;
 mod shell;
 mod shortcut;
+mod sixel;
 mod snap;
 mod term;
 mod utils;
@@ -18,3 +22,4 @@ mod x11_wrapper;
 // FIXME: put all config into one mod
 
 pub mod app;
+pub mod config;
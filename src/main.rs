@@ -1,4 +1,5 @@
 use rterm::app::App;
+use rterm::config::{ClipboardPolicy, Config, FilePolicy};
 
 use std::process::exit;
 
@@ -14,14 +15,32 @@ struct AppArg {
     font: Option<String>,
     #[clap(short = 'o', long)]
     log: Option<String>,
+    /// Access of applications to the clipboard with OSC 52, allow-read
+    /// implies allow-write
+    #[clap(long, value_enum, default_value = "allow-write")]
+    clipboard: ClipboardPolicy,
+    /// Files the kitty graphics protocol may read images from, allow
+    /// implies temp
+    #[clap(long, value_enum, default_value = "temp")]
+    kitty_files: FilePolicy,
+    /// xterm formatOtherKeys of modifyOtherKeys, 0 for "CSI 27;mod;code~"
+    /// or 1 for "CSI code;mod u"
+    #[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=1))]
+    format_other_keys: u8,
 }
 
 fn _main() -> Result<()> {
     let arg: AppArg = AppArg::parse();
+    let config = Config {
+        clipboard: arg.clipboard,
+        kitty_files: arg.kitty_files,
+        format_other_keys: arg.format_other_keys,
+    };
     let mut app = App::new(
         arg.geometry.as_deref(),
         arg.font.as_deref(),
         arg.log.as_deref(),
+        config,
     )?;
     app.run()?;
 
@@ -9,12 +9,16 @@ use x11::xlib::*;
 #[derive(Clone, Copy)]
 pub enum Function {
     Paste,
+    ScrollUp,
+    ScrollDown,
 }
 
 impl Function {
-    pub fn execute(&self, win: &mut Win, _term: &mut Term) {
+    pub fn execute(&self, win: &mut Win, term: &mut Term) {
         match self {
             Function::Paste => win.selection_paste(),
+            Function::ScrollUp => term.scroll_view(term.rows as i32),
+            Function::ScrollDown => term.scroll_view(-(term.rows as i32)),
         }
     }
 }
@@ -42,6 +46,8 @@ macro_rules! make_shortcuts {
 const SHORTCUTS: &[Shortcut] = make_shortcuts! {
     /* mask                  keysym          function */
     { ShiftMask,             XK_Insert,      Function::Paste },
+    { ShiftMask,             XK_Prior,       Function::ScrollUp },
+    { ShiftMask,             XK_Next,        Function::ScrollDown },
 };
 
 pub fn find_shortcut(k: KeySym, state: c_uint) -> Option<Function> {
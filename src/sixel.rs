@@ -0,0 +1,292 @@
+// sixel graphics references:
+// - https://vt100.net/docs/vt3xx-gp/chapter14.html
+// - https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h2-Sixel-Graphics
+
+use crate::image::Image;
+
+pub const SIXEL_COLORS: usize = 256;
+pub const SIXEL_WIDTH_MAX: usize = 4096;
+pub const SIXEL_HEIGHT_MAX: usize = 4096;
+
+// VT340 default color map, in percent
+const DEFAULT_COLORS: [(u32, u32, u32); 16] = [
+    (0, 0, 0),
+    (20, 20, 80),
+    (80, 13, 13),
+    (20, 80, 20),
+    (80, 20, 80),
+    (20, 80, 80),
+    (80, 80, 20),
+    (53, 53, 53),
+    (26, 26, 26),
+    (33, 33, 60),
+    (60, 26, 26),
+    (33, 60, 33),
+    (60, 33, 60),
+    (33, 60, 60),
+    (60, 60, 33),
+    (80, 80, 80),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Data,
+    // "!Pn" repeat introducer
+    Repeat,
+    // "#Pc;Pu;Px;Py;Pz" color introducer
+    Color,
+    // "\"Pan;Pad;Ph;Pv" raster attributes
+    Raster,
+}
+
+// Decode sixel data as it is received.
+pub struct SixelParser {
+    state: State,
+    params: Vec<u32>,
+    palette: [u32; SIXEL_COLORS],
+    color: usize,
+    // position of the current sixel, y is the top row of the band
+    x: usize,
+    y: usize,
+    // RGBA pixels, 0 is transparent
+    rows: Vec<Vec<u32>>,
+    width: usize,
+    height: usize,
+}
+
+impl SixelParser {
+    pub fn new() -> Self {
+        let mut palette = [0; SIXEL_COLORS];
+        for (i, &(r, g, b)) in DEFAULT_COLORS.iter().enumerate() {
+            palette[i] = rgba(percent(r), percent(g), percent(b));
+        }
+
+        SixelParser {
+            state: State::Data,
+            params: Vec::new(),
+            palette,
+            color: 0,
+            x: 0,
+            y: 0,
+            rows: Vec::new(),
+            width: 0,
+            height: 0,
+        }
+    }
+
+    pub fn put(&mut self, byte: u8) {
+        if self.state != State::Data {
+            match byte {
+                b'0'..=b'9' => {
+                    let p = self.params.last_mut().unwrap();
+                    *p = p.saturating_mul(10).saturating_add((byte - b'0') as u32);
+                    return;
+                }
+                b';' => {
+                    self.params.push(0);
+                    return;
+                }
+                _ => self.finish_command(byte),
+            }
+        }
+
+        match byte {
+            b'?'..=b'~' => {
+                let n = if self.state == State::Repeat {
+                    self.params[0].max(1) as usize
+                } else {
+                    1
+                };
+                self.state = State::Data;
+                self.put_sixel(byte - b'?', n);
+            }
+            b'!' => self.start_command(State::Repeat),
+            b'#' => self.start_command(State::Color),
+            b'"' => self.start_command(State::Raster),
+            // graphics carriage return
+            b'$' => self.x = 0,
+            // graphics new line
+            b'-' => {
+                self.x = 0;
+                self.y += 6;
+            }
+            _ => (),
+        }
+    }
+
+    fn start_command(&mut self, state: State) {
+        self.state = state;
+        self.params.clear();
+        self.params.push(0);
+    }
+
+    fn finish_command(&mut self, byte: u8) {
+        match self.state {
+            State::Color => {
+                let p = &self.params;
+                let idx = p[0] as usize % SIXEL_COLORS;
+                if p.len() >= 5 {
+                    let (x, y, z) = (p[2], p[3].min(100), p[4].min(100));
+                    self.palette[idx] = match p[1] {
+                        1 => hls(x % 360, y, z),
+                        2 => rgba(percent(x.min(100)), percent(y), percent(z)),
+                        _ => self.palette[idx],
+                    };
+                }
+                self.color = idx;
+            }
+            State::Raster => {
+                // Ph and Pv give the background area, it is left transparent
+                if let (Some(&w), Some(&h)) = (self.params.get(2), self.params.get(3)) {
+                    self.width = self.width.max((w as usize).min(SIXEL_WIDTH_MAX));
+                    self.height = self.height.max((h as usize).min(SIXEL_HEIGHT_MAX));
+                }
+            }
+            // a repeat is finished by the sixel it repeats
+            State::Repeat if matches!(byte, b'?'..=b'~') => return,
+            _ => (),
+        }
+        self.state = State::Data;
+    }
+
+    fn put_sixel(&mut self, bits: u8, n: usize) {
+        let x0 = self.x;
+        let x1 = (x0 + n).min(SIXEL_WIDTH_MAX);
+        self.x += n;
+        if bits == 0 || x0 >= x1 {
+            return;
+        }
+
+        let color = self.palette[self.color];
+        for i in 0..6 {
+            let y = self.y + i;
+            if bits & (1 << i) == 0 || y >= SIXEL_HEIGHT_MAX {
+                continue;
+            }
+            if self.rows.len() <= y {
+                self.rows.resize(y + 1, Vec::new());
+            }
+            let row = &mut self.rows[y];
+            if row.len() < x1 {
+                row.resize(x1, 0);
+            }
+            row[x0..x1].fill(color);
+            self.height = self.height.max(y + 1);
+        }
+        self.width = self.width.max(x1);
+    }
+
+    pub fn finish(self) -> Option<Image> {
+        if self.width == 0 || self.height == 0 {
+            return None;
+        }
+
+        let mut data = vec![0; self.width * self.height * 4];
+        for (y, row) in self.rows.iter().enumerate() {
+            for (x, &pixel) in row.iter().enumerate() {
+                let i = (y * self.width + x) * 4;
+                data[i..i + 4].copy_from_slice(&pixel.to_be_bytes());
+            }
+        }
+        Some(Image::new(self.width, self.height, data))
+    }
+}
+
+fn percent(v: u32) -> u32 {
+    (v * 255 + 50) / 100
+}
+
+fn rgba(r: u32, g: u32, b: u32) -> u32 {
+    r << 24 | g << 16 | b << 8 | 0xFF
+}
+
+// Convert sixel HLS to RGB, the hue starts at blue instead of red.
+fn hls(h: u32, l: u32, s: u32) -> u32 {
+    let h = ((h + 240) % 360) as f64 / 360.0;
+    let l = l as f64 / 100.0;
+    let s = s as f64 / 100.0;
+
+    if s == 0.0 {
+        let v = (l * 255.0).round() as u32;
+        return rgba(v, v, v);
+    }
+
+    let q = if l < 0.5 { l * (1.0 + s) } else { l + s - l * s };
+    let p = 2.0 * l - q;
+    let channel = |t: f64| {
+        let t = t.rem_euclid(1.0);
+        let v = if t < 1.0 / 6.0 {
+            p + (q - p) * 6.0 * t
+        } else if t < 0.5 {
+            q
+        } else if t < 2.0 / 3.0 {
+            p + (q - p) * (2.0 / 3.0 - t) * 6.0
+        } else {
+            p
+        };
+        (v * 255.0).round() as u32
+    };
+
+    rgba(channel(h + 1.0 / 3.0), channel(h), channel(h - 1.0 / 3.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(data: &[u8]) -> Option<Image> {
+        let mut parser = SixelParser::new();
+        data.iter().for_each(|&b| parser.put(b));
+        parser.finish()
+    }
+
+    fn pixel(image: &Image, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * image.width + x) * 4;
+        image.data[i..i + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn color_and_repeat() {
+        let image = decode(b"#1;2;100;0;0#1~!3~").unwrap();
+        assert_eq!((image.width, image.height), (4, 6));
+        for y in 0..6 {
+            for x in 0..4 {
+                assert_eq!(pixel(&image, x, y), [255, 0, 0, 255]);
+            }
+        }
+    }
+
+    #[test]
+    fn bands_and_carriage_return() {
+        // the top pixel of the first band, and the bottom one of the second
+        let image = decode(b"#2;2;0;100;0#2@$?@-_").unwrap();
+        assert_eq!((image.width, image.height), (2, 12));
+        assert_eq!(pixel(&image, 0, 0), [0, 255, 0, 255]);
+        assert_eq!(pixel(&image, 1, 0), [0, 255, 0, 255]);
+        assert_eq!(pixel(&image, 0, 1), [0, 0, 0, 0]);
+        assert_eq!(pixel(&image, 0, 11), [0, 255, 0, 255]);
+        assert_eq!(pixel(&image, 1, 11), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn raster_attributes_set_the_size() {
+        let image = decode(b"\"1;1;8;12#0~").unwrap();
+        assert_eq!((image.width, image.height), (8, 12));
+        assert_eq!(pixel(&image, 7, 11), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn empty_image() {
+        assert!(decode(b"").is_none());
+        assert!(decode(b"#1?!10?").is_none());
+    }
+
+    #[test]
+    fn hls_colors() {
+        // the hue starts at blue
+        assert_eq!(hls(0, 50, 100), rgba(0, 0, 255));
+        assert_eq!(hls(120, 50, 100), rgba(255, 0, 0));
+        assert_eq!(hls(240, 50, 100), rgba(0, 255, 0));
+        assert_eq!(hls(0, 100, 0), rgba(255, 255, 255));
+    }
+}
@@ -1,22 +1,52 @@
-// FIXME: support wide chars
-
 use crate::charset::CharsetTable;
+use crate::cluster::ClusterTable;
 use crate::color::{BG_COLOR, FG_COLOR, CURSOR_COLOR, CURSOR_REV_COLOR};
 use crate::cursor::Cursor;
 use crate::glyph::{blank_glyph, Glyph, GlyphAttr, GlyphProp};
+use crate::image::{Image, ImageCell, ImageTable};
 use crate::point::Point;
 use crate::snap::{is_delim, SnapMode};
 use crate::utils::{is_between, limit, sort_pair};
 
 use std::cmp;
+use std::collections::{HashSet, VecDeque};
 use std::mem;
 
 use anyhow::Result;
 use bitflags::bitflags;
 use unicode_width::UnicodeWidthChar;
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum SelType {
+    Regular,
+    Rectangular,
+}
+
+// Where an image is put and where the cursor goes afterwards.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ImageCursor {
+    // at the top left, the cursor does not move
+    Home,
+    // at the cursor, which moves to the line below the image
+    NextLine,
+    // at the cursor, which moves right of the image on its last row
+    AfterImage,
+    // at the cursor without scrolling, the cursor does not move
+    Stay,
+}
+
+// A rectangle of screen cells, the bounds are inclusive.
+#[derive(Clone, Copy)]
+pub struct Rect {
+    pub top: usize,
+    pub left: usize,
+    pub bot: usize,
+    pub right: usize,
+}
+
 struct Selection {
     pub mode: SnapMode,
+    pub sel_type: SelType,
     pub empty: bool,
     pub ob: Point,
     pub oe: Point,
@@ -28,6 +58,7 @@ impl Selection {
     pub fn new() -> Self {
         Selection {
             mode: SnapMode::None,
+            sel_type: SelType::Regular,
             empty: true,
             ob: Point::new(0, 0),
             oe: Point::new(0, 0),
@@ -38,19 +69,40 @@ impl Selection {
 }
 
 bitflags! {
+    #[derive(Clone, Copy)]
     pub struct TermMode: u32 {
         const WRAP        = 1 << 0;
         const INSERT      = 1 << 1;
         const ORIGIN      = 1 << 2;
         const CRLF        = 1 << 3;
+        // DECSDM, sixel images are drawn at the top left without scrolling
+        const SIXEL_DISPLAY = 1 << 4;
+        // DECLRMM, DECSLRM sets the left and right margins
+        const LRMARGIN    = 1 << 5;
+        // DECSACE, attributes are changed in a rectangle instead of a stream
+        const RECT_EXTENT = 1 << 6;
     }
 }
 
+// modes saved by DECSC
+const SAVED_MODES: TermMode = TermMode::ORIGIN.union(TermMode::WRAP);
+
+// The state saved by DECSC and restored by DECRC.
+#[derive(Clone, Copy)]
+struct SavedCursor {
+    c: Cursor,
+    prop: GlyphProp,
+    charset: CharsetTable,
+    mode: TermMode,
+}
+
 const COLS_MIN: usize = 2;
 const COLS_MAX: usize = u16::MAX as usize;
 const ROWS_MIN: usize = 1;
 const ROWS_MAX: usize = u16::MAX as usize;
 const TAB_STOP: usize = 8;
+// max number of lines kept in the scrollback history
+const HISTORY_SIZE: usize = 2000;
 
 pub struct Term {
     pub rows: usize,
@@ -58,18 +110,26 @@ pub struct Term {
     pub c: Cursor,
     pub scroll_top: usize,
     pub scroll_bot: usize,
+    pub scroll_left: usize,
+    pub scroll_right: usize,
     pub charset: CharsetTable,
     pub prop: GlyphProp,
-    saved_c: Option<Cursor>,
-    alt_saved_c: Option<Cursor>,
+    saved_c: Option<SavedCursor>,
+    alt_saved_c: Option<SavedCursor>,
     lines: Vec<Vec<Glyph>>,
     alt_lines: Vec<Vec<Glyph>>,
+    // lines scrolled off the top of the primary screen, oldest first
+    history: VecDeque<Vec<Glyph>>,
+    // number of history lines shown above the screen
+    scroll_offset: usize,
     is_alt_screen: bool,
     dirty: Vec<bool>,
     tabs: Vec<bool>,
     mode: TermMode,
     sel: Selection,
     titles: Vec<String>,
+    clusters: ClusterTable,
+    images: ImageTable,
 }
 
 impl Term {
@@ -81,9 +141,13 @@ impl Term {
             is_alt_screen: false,
             lines: Vec::new(),
             alt_lines: Vec::new(),
+            history: VecDeque::new(),
+            scroll_offset: 0,
             dirty: Vec::new(),
             scroll_top: 0,
             scroll_bot: 0,
+            scroll_left: 0,
+            scroll_right: 0,
             charset: CharsetTable::new(),
             prop: GlyphProp::new(FG_COLOR, BG_COLOR, GlyphAttr::empty()),
             mode: TermMode::WRAP,
@@ -92,6 +156,8 @@ impl Term {
             saved_c: None,
             alt_saved_c: None,
             titles: Vec::new(),
+            clusters: ClusterTable::new(),
+            images: ImageTable::new(),
         };
 
         term.resize(cols, rows);
@@ -106,27 +172,28 @@ impl Term {
             return false;
         }
 
-        if !self.sel.empty {
-            self.clear_selection();
-        }
-
-        if self.c.y > rows - 1 {
-            self.scroll_up(0, self.c.y - rows + 1)
-        }
-
-        self.lines.resize_with(rows, Vec::new);
-        self.lines.shrink_to_fit();
-        for line in self.lines.iter_mut() {
-            line.resize(cols, blank_glyph());
-            line.shrink_to_fit();
-        }
-
-        self.alt_lines.resize_with(rows, Vec::new);
-        self.alt_lines.shrink_to_fit();
-        for line in self.alt_lines.iter_mut() {
-            line.resize(cols, blank_glyph());
-            line.shrink_to_fit();
+        if self.is_alt_screen {
+            if !self.sel.empty {
+                self.clear_selection();
+            }
+            if self.c.y > rows - 1 {
+                self.scroll_up(0, self.c.y - rows + 1)
+            }
+            Self::resize_lines(&mut self.lines, cols, rows);
+
+            let mut cursors: Vec<Point> = self.alt_saved_c
+                .iter()
+                .map(|s| Point::new(s.c.x, s.c.y + self.history.len()))
+                .collect();
+            self.reflow(cols, rows, &mut cursors, &mut []);
+            if let (Some(s), Some(p)) = (&mut self.alt_saved_c, cursors.first()) {
+                s.c.x = p.x;
+                s.c.y = p.y - self.history.len();
+            }
+        } else {
+            self.resize_primary(cols, rows);
         }
+        self.scroll_offset = 0;
 
         self.dirty.resize(rows, true);
         self.dirty.shrink_to_fit();
@@ -144,26 +211,278 @@ impl Term {
 
         self.scroll_top = 0;
         self.scroll_bot = rows - 1;
+        self.scroll_left = 0;
+        self.scroll_right = cols - 1;
         self.cols = cols;
         self.rows = rows;
 
+        if !self.sel.empty {
+            self.normalize_selection();
+        }
+
         // relocate cursor
+        let wrap_next = self.c.wrap_next;
         self.move_to(self.c.x, self.c.y);
+        self.c.wrap_next = wrap_next && self.c.x == cols - 1;
         true
     }
 
+    fn resize_lines(lines: &mut Vec<Vec<Glyph>>, cols: usize, rows: usize) {
+        lines.resize_with(rows, Vec::new);
+        lines.shrink_to_fit();
+        for line in lines.iter_mut() {
+            line.resize(cols, blank_glyph());
+            line.shrink_to_fit();
+        }
+    }
+
+    // Resize while the primary screen is shown, moving the cursors and
+    // the selection along with the reflowed text.
+    fn resize_primary(&mut self, cols: usize, rows: usize) {
+        Self::resize_lines(&mut self.alt_lines, cols, rows);
+
+        let hist = self.history.len();
+        let mut cursors = vec![Point::new(self.c.x, self.c.y + hist)];
+        if let Some(s) = self.saved_c {
+            cursors.push(Point::new(s.c.x, s.c.y + hist));
+        }
+
+        let mut marks = Vec::new();
+        if !self.sel.empty {
+            let top = hist - self.scroll_offset;
+            marks.push(Point::new(self.sel.ob.x, self.sel.ob.y + top));
+            marks.push(Point::new(self.sel.oe.x, self.sel.oe.y + top));
+            self.clear_selection();
+        }
+
+        self.reflow(cols, rows, &mut cursors, &mut marks);
+        let hist = self.history.len();
+
+        // A cursor waiting to wrap stays at the end of the text.
+        let p = cursors[0];
+        self.c.y = p.y - hist;
+        if self.c.wrap_next && p.x < cols - 1 {
+            self.c.x = p.x + 1;
+            self.c.wrap_next = false;
+        } else {
+            self.c.x = p.x;
+        }
+        if let (Some(s), Some(p)) = (&mut self.saved_c, cursors.get(1)) {
+            s.c.x = p.x;
+            s.c.y = p.y - hist;
+        }
+
+        // the selection is normalized by the caller once the size is set
+        if marks.len() == 2 && marks.iter().all(|p| p.y >= hist) {
+            self.sel.ob = Point::new(marks[0].x, marks[0].y - hist);
+            self.sel.oe = Point::new(marks[1].x, marks[1].y - hist);
+            self.sel.empty = false;
+        }
+    }
+
+    // Rejoin the soft-wrapped lines of the primary screen and its history
+    // and rewrap them to the new size.  `cursors` and `marks` are positions
+    // counted from the first history line, they are moved along with the
+    // text.  The cells under the cursors are kept, while marks are limited
+    // to the existing text.  The screen follows the first cursor, the others
+    // are moved into it.
+    fn reflow(
+        &mut self,
+        cols: usize,
+        rows: usize,
+        cursors: &mut [Point],
+        marks: &mut [Point],
+    ) {
+        let primary = if self.is_alt_screen {
+            &mut self.alt_lines
+        } else {
+            &mut self.lines
+        };
+        let mut old: Vec<Vec<Glyph>> = self.history.drain(..).collect();
+        old.append(primary);
+
+        // join the physical lines into logical lines
+        let mut logical: Vec<Vec<Glyph>> = Vec::new();
+        let mut pos = Vec::with_capacity(old.len());
+        let mut cur = Vec::new();
+        for mut line in old {
+            pos.push((logical.len(), cur.len()));
+            let wrapped = match line.last_mut() {
+                Some(g) => {
+                    let wrapped = g.prop.attr.contains(GlyphAttr::WRAP);
+                    g.prop.attr.remove(GlyphAttr::WRAP);
+                    wrapped
+                }
+                None => false,
+            };
+            // drop the padding left by a wide char that did not fit
+            let len = line.len();
+            if wrapped
+                && line[len - 1].prop.attr.contains(GlyphAttr::DUMMY)
+                && !Self::is_wide_dummy(&line, len - 1)
+            {
+                line.pop();
+            }
+            cur.append(&mut line);
+            if !wrapped {
+                logical.push(mem::take(&mut cur));
+            }
+        }
+        if !cur.is_empty() {
+            logical.push(cur);
+        }
+
+        let to_logical = |p: &Point| match pos.get(p.y) {
+            Some(&(l, off)) => (l, off + p.x),
+            None => (logical.len(), p.x),
+        };
+        let cursors_l: Vec<(usize, usize)> = cursors.iter().map(to_logical).collect();
+        let marks_l: Vec<(usize, usize)> = marks.iter().map(to_logical).collect();
+
+        let last_cursor = cursors_l.iter().map(|&(l, _)| l).max();
+        if let Some(l) = last_cursor {
+            logical.resize_with(cmp::max(logical.len(), l + 1), Vec::new);
+        }
+
+        // trim the blank tail, keeping the cells under the cursors
+        let blank = blank_glyph();
+        for (l, line) in logical.iter_mut().enumerate() {
+            let mut len = line.len();
+            while len > 0 && line[len - 1].c == ' '
+                && line[len - 1].cluster == 0
+                && line[len - 1].image.is_none()
+                && line[len - 1].prop == blank.prop {
+                len -= 1;
+            }
+            for &(cl, off) in cursors_l.iter() {
+                if cl == l {
+                    len = cmp::max(len, off + 1);
+                }
+            }
+            line.resize(len, blank);
+        }
+
+        // drop the empty lines below the cursors
+        while logical.len() > last_cursor.map_or(0, |l| l + 1)
+            && logical.last().is_some_and(|l| l.is_empty())
+        {
+            logical.pop();
+        }
+
+        // rewrap, recording the physical line and the offset each part of
+        // the logical lines starts at
+        let mut lines = Vec::new();
+        let mut breaks = Vec::with_capacity(logical.len());
+        for line in logical {
+            let mut parts = vec![(lines.len(), 0)];
+            let mut new_line = Vec::with_capacity(cols);
+            let mut i = 0;
+            while i < line.len() {
+                let w = if line[i].prop.attr.contains(GlyphAttr::WIDE) {
+                    cmp::min(2, line.len() - i)
+                } else {
+                    1
+                };
+                if new_line.len() + w > cols {
+                    if new_line.len() < cols {
+                        let mut pad = blank;
+                        pad.prop.attr.insert(GlyphAttr::DUMMY);
+                        new_line.push(pad);
+                    }
+                    new_line[cols - 1].prop.attr.insert(GlyphAttr::WRAP);
+                    lines.push(mem::replace(&mut new_line, Vec::with_capacity(cols)));
+                    parts.push((lines.len(), i));
+                }
+                new_line.extend_from_slice(&line[i..i + w]);
+                i += w;
+            }
+            new_line.resize(cols, blank);
+            lines.push(new_line);
+            breaks.push(parts);
+        }
+
+        let to_physical = |(l, off): (usize, usize)| {
+            let parts: &Vec<(usize, usize)> = &breaks[l];
+            let (y, start) = parts[parts.partition_point(|&(_, start)| start <= off) - 1];
+            Point::new(cmp::min(off - start, cols - 1), y)
+        };
+        for (p, &pl) in cursors.iter_mut().zip(cursors_l.iter()) {
+            *p = to_physical(pl);
+        }
+        for (p, &pl) in marks.iter_mut().zip(marks_l.iter()) {
+            *p = to_physical(pl);
+        }
+
+        // fill the screen from the bottom, keeping the first cursor visible
+        let mut top = lines.len().saturating_sub(rows);
+        if let Some(p) = cursors.first() {
+            top = cmp::min(top, p.y);
+        }
+        // the saved cursors are moved into the screen
+        for p in cursors.iter_mut().skip(1) {
+            p.y = limit(p.y, top, top + rows - 1);
+        }
+        let drop = top.saturating_sub(HISTORY_SIZE);
+        let mut screen = lines.split_off(top);
+        screen.resize_with(rows, || vec![blank; cols]);
+
+        self.history = lines.into_iter().skip(drop).collect();
+        *primary = screen;
+        for p in cursors.iter_mut().chain(marks.iter_mut()) {
+            p.y = p.y.saturating_sub(drop);
+        }
+    }
+
     pub fn set_mode(&mut self, mode: TermMode, val: bool) {
+        if mode.contains(TermMode::LRMARGIN) && !val {
+            self.scroll_left = 0;
+            self.scroll_right = self.cols - 1;
+        }
         self.mode.set(mode, val);
     }
 
+    pub fn get_mode(&self, mode: TermMode) -> bool {
+        self.mode.contains(mode)
+    }
+
+    // x, y are in view coordinates, i.e. with the scroll offset applied.
     pub fn get_glyph(&self, x: usize, y: usize) -> Glyph {
-        let mut g = self.lines[y][x];
+        let mut g = self.view_line(y)[x];
         g.prop = g.prop.resolve(self.is_selected(x, y));
         g
     }
 
-    pub fn get_glyph_at_cursor(&self) -> Glyph {
+    // Get the glyph drawn over the cell at x, y along with the column it
+    // starts at, which is the left half for wide chars.
+    pub fn get_cell(&self, x: usize, y: usize) -> (usize, Glyph) {
+        let mut g = self.get_glyph(x, y);
+        if g.prop.attr.contains(GlyphAttr::DUMMY) {
+            if Self::is_wide_dummy(self.view_line(y), x) {
+                return (x - 1, self.get_glyph(x - 1, y));
+            }
+            g.c = ' ';
+            g.prop.attr.remove(GlyphAttr::DUMMY);
+        }
+        (x, g)
+    }
+
+    // The cursor is drawn over the whole wide char it is on.
+    pub fn cursor_cell(&self) -> (usize, usize) {
         let (x, y) = (self.c.x, self.c.y);
+        if Self::is_wide_dummy(&self.lines[y], x) {
+            (x - 1, y)
+        } else {
+            (x, y)
+        }
+    }
+
+    // The zero-width chars attached to the glyph.
+    pub fn cluster(&self, g: &Glyph) -> &str {
+        self.clusters.get(g.cluster)
+    }
+
+    pub fn get_glyph_at_cursor(&self) -> Glyph {
+        let (x, y) = self.cursor_cell();
         let mut g = self.lines[y][x];
         if self.is_selected(x, y) {
             g.prop.bg = CURSOR_REV_COLOR;
@@ -182,6 +501,9 @@ impl Term {
         self.c.reset();
         self.scroll_top = 0;
         self.scroll_bot = self.rows - 1;
+        self.scroll_left = 0;
+        self.scroll_right = self.cols - 1;
+        self.reset_view();
     }
 
     pub fn set_scroll(&mut self, top: usize, bot: usize) {
@@ -193,6 +515,46 @@ impl Term {
         self.scroll_bot = bot;
     }
 
+    // DECSLRM, margins that do not leave 2 columns are ignored, otherwise
+    // the cursor goes home.
+    pub fn set_lr_margins(&mut self, left: usize, right: usize) {
+        let right = cmp::min(right, self.cols - 1);
+        if left >= right {
+            return;
+        }
+
+        self.scroll_left = left;
+        self.scroll_right = right;
+        self.move_ato(0, 0);
+    }
+
+    fn has_lr_margins(&self) -> bool {
+        self.scroll_left > 0 || self.scroll_right < self.cols - 1
+    }
+
+    fn in_lr_margins(&self) -> bool {
+        is_between(self.c.x, self.scroll_left, self.scroll_right)
+    }
+
+    // The column a carriage return goes to.
+    pub fn line_start(&self) -> usize {
+        if self.c.x >= self.scroll_left {
+            self.scroll_left
+        } else {
+            0
+        }
+    }
+
+    // The column after the last one the cursor can write to before it
+    // wraps.
+    fn line_end(&self) -> usize {
+        if self.c.x <= self.scroll_right {
+            self.scroll_right + 1
+        } else {
+            self.cols
+        }
+    }
+
     pub fn set_dirty<R: Iterator<Item = usize>>(&mut self, range: R, v: bool) {
         for i in range {
             self.dirty[i] = v;
@@ -204,8 +566,13 @@ impl Term {
             return true;
         }
 
-        for g in self.lines[i].iter() {
-            if g.prop.attr.contains(GlyphAttr::BLINK) {
+        // screen line shown at view line i
+        if i >= self.scroll_offset && self.dirty[i - self.scroll_offset] {
+            return true;
+        }
+
+        for g in self.view_line(i).iter() {
+            if g.prop.attr.intersects(GlyphAttr::BLINK | GlyphAttr::RAPID_BLINK) {
                 return true;
             }
         }
@@ -214,23 +581,143 @@ impl Term {
     }
 
     pub fn clear_region<R1, R2>(&mut self, xrange: R1, yrange: R2)
+    where
+        R1: Iterator<Item = usize> + Clone,
+        R2: Iterator<Item = usize>,
+    {
+        self.erase_region(xrange, yrange, false)
+    }
+
+    // Clear the region, except for the protected cells if selective.
+    pub fn erase_region<R1, R2>(&mut self, xrange: R1, yrange: R2, selective: bool)
     where
         R1: Iterator<Item = usize> + Clone,
         R2: Iterator<Item = usize>,
     {
         let mut glyph = blank_glyph();
         glyph.prop = self.prop;
+        let first = xrange.clone().next();
+        let last = xrange.clone().last();
         for y in yrange {
             self.dirty[y] = true;
+            let protected = |line: &[Glyph], x: usize| {
+                selective && line[x].prop.attr.contains(GlyphAttr::PROTECTED)
+            };
+            // wide chars cut by the region are cleared as a whole
+            for x in first.into_iter().chain(last) {
+                if !protected(&self.lines[y], x) {
+                    self.split_wide(x, y);
+                }
+            }
             for x in xrange.clone() {
+                if protected(&self.lines[y], x) {
+                    continue;
+                }
                 self.lines[y][x].clear(glyph);
-                if self.is_selected(x, y) {
+                if self.is_screen_selected(x, y) {
                     self.clear_selection();
                 }
             }
         }
     }
 
+    // The rectangle at the 1-based coordinates of a rectangular area
+    // operation, where 0 is the default.  The coordinates are relative to
+    // the margins in origin mode.  None if the rectangle is empty.
+    pub fn area(&self, top: u16, left: u16, bot: u16, right: u16) -> Option<Rect> {
+        let (y0, x0, y1, x1) = if self.mode.contains(TermMode::ORIGIN) {
+            (self.scroll_top, self.scroll_left, self.scroll_bot, self.scroll_right)
+        } else {
+            (0, 0, self.rows - 1, self.cols - 1)
+        };
+        let pos = |p: u16, origin: usize, max: usize, default: usize| match p {
+            0 => default,
+            p => cmp::min(p as usize - 1 + origin, max),
+        };
+
+        let rect = Rect {
+            top: pos(top, y0, y1, y0),
+            left: pos(left, x0, x1, x0),
+            bot: pos(bot, y0, y1, y1),
+            right: pos(right, x0, x1, x1),
+        };
+        (rect.top <= rect.bot && rect.left <= rect.right).then_some(rect)
+    }
+
+    // DECFRA, fill the rectangle with c in the current attributes.
+    pub fn fill_rect(&mut self, rect: Rect, c: char) {
+        self.clear_region(rect.left..=rect.right, rect.top..=rect.bot);
+        let prop = self.prop;
+        for line in self.lines[rect.top..=rect.bot].iter_mut() {
+            for g in line[rect.left..=rect.right].iter_mut() {
+                g.c = c;
+                g.prop = prop;
+            }
+        }
+    }
+
+    // DECCRA, copy the rectangle to the cells from top, left, clipped by
+    // the screen, or by the margins in origin mode.
+    pub fn copy_rect(&mut self, src: Rect, top: usize, left: usize) {
+        let (bot, right) = if self.mode.contains(TermMode::ORIGIN) {
+            (self.scroll_bot, self.scroll_right)
+        } else {
+            (self.rows - 1, self.cols - 1)
+        };
+        let h = cmp::min(src.bot - src.top, bot - top) + 1;
+        let w = cmp::min(src.right - src.left, right - left) + 1;
+
+        // copied first, the rectangles may overlap
+        let mut cells: Vec<Vec<Glyph>> = self.lines[src.top..src.top + h]
+            .iter()
+            .map(|line| line[src.left..src.left + w].to_vec())
+            .collect();
+        for row in cells.iter_mut() {
+            // wide chars cut by the rectangle are not copied
+            let g = &mut row[0];
+            if g.prop.attr.contains(GlyphAttr::DUMMY) {
+                g.c = ' ';
+                g.prop.attr.remove(GlyphAttr::DUMMY);
+            }
+            let g = &mut row[w - 1];
+            if g.prop.attr.contains(GlyphAttr::WIDE) {
+                g.c = ' ';
+                g.cluster = 0;
+                g.prop.attr.remove(GlyphAttr::WIDE);
+            }
+        }
+
+        for (y, row) in (top..).zip(cells) {
+            self.split_wide(left, y);
+            self.split_wide(left + w - 1, y);
+            self.lines[y][left..left + w].copy_from_slice(&row);
+            self.dirty[y] = true;
+        }
+        if !self.sel.empty {
+            self.clear_selection();
+        }
+    }
+
+    // DECCARA and DECRARA, change the attributes of the cells in the
+    // rectangle, or from its top left to its bottom right in stream extent.
+    pub fn change_attrs<F: Fn(&mut GlyphAttr)>(&mut self, rect: Rect, f: F) {
+        let stream = !self.mode.contains(TermMode::RECT_EXTENT);
+        for y in rect.top..=rect.bot {
+            let (left, right) = if stream {
+                (
+                    if y == rect.top { rect.left } else { 0 },
+                    if y == rect.bot { rect.right } else { self.cols - 1 },
+                )
+            } else {
+                (rect.left, rect.right)
+            };
+            for g in self.lines[y][left..=right].iter_mut() {
+                f(&mut g.prop.attr);
+            }
+            self.dirty[y] = true;
+        }
+    }
+
     pub fn clear_screen(&mut self) {
         self.clear_region(0..self.cols, 0..self.rows)
     }
@@ -241,17 +728,26 @@ impl Term {
 
     pub fn new_line(&mut self, first_col: bool) {
         if self.c.y == self.scroll_bot {
-            self.scroll_up(self.scroll_top, 1);
+            // nothing scrolls outside of the left and right margins
+            if self.in_lr_margins() {
+                self.scroll_up(self.scroll_top, 1);
+            }
         } else {
             self.c.y += 1;
         }
 
         if first_col {
-            self.c.x = 0;
+            self.c.x = self.line_start();
         }
     }
 
     pub fn scroll_up(&mut self, orig: usize, n: usize) {
+        self.scroll_up_lines(orig, n, true);
+    }
+
+    // Lines scrolled off the top of the primary screen go into the history
+    // if to_history, the lines deleted by DL do not.
+    fn scroll_up_lines(&mut self, orig: usize, n: usize, to_history: bool) {
         assert!(is_between(orig, self.scroll_top, self.scroll_bot));
         if n < 1 {
             return;
@@ -259,6 +755,19 @@ impl Term {
         let bottom = self.scroll_bot;
         let n = cmp::min(n, bottom - orig + 1);
 
+        if self.has_lr_margins() {
+            for y in orig..bottom + 1 - n {
+                self.copy_line_part(y + n, y);
+            }
+            self.clear_region(self.scroll_left..=self.scroll_right, bottom + 1 - n..=bottom);
+            self.scroll_selection(orig, -(n as i32));
+            return;
+        }
+
+        if to_history && orig == 0 && !self.is_alt_screen {
+            self.push_history(n);
+        }
+
         self.clear_lines(orig..orig + n);
         self.set_dirty(orig + n..=bottom, true);
         self.lines[orig..=bottom].rotate_left(n);
@@ -274,6 +783,15 @@ impl Term {
         let bottom = self.scroll_bot;
         let n = cmp::min(n, bottom - orig + 1);
 
+        if self.has_lr_margins() {
+            for y in (orig + n..=bottom).rev() {
+                self.copy_line_part(y - n, y);
+            }
+            self.clear_region(self.scroll_left..=self.scroll_right, orig..orig + n);
+            self.scroll_selection(orig, n as i32);
+            return;
+        }
+
         self.set_dirty(orig..bottom - n + 1, true);
         self.clear_lines(bottom - n + 1..=self.scroll_bot);
         self.lines[orig..=bottom].rotate_right(n);
@@ -281,53 +799,130 @@ impl Term {
         self.scroll_selection(orig, n as i32);
     }
 
+    // Copy the part of line src within the left and right margins to
+    // line dst.
+    fn copy_line_part(&mut self, src: usize, dst: usize) {
+        let (left, right) = (self.scroll_left, self.scroll_right);
+        // wide chars cut by the margins are cleared as a whole
+        for y in [src, dst] {
+            self.split_wide(left, y);
+            self.split_wide(right, y);
+        }
+        let part = self.lines[src][left..=right].to_vec();
+        self.lines[dst][left..=right].copy_from_slice(&part);
+        self.dirty[dst] = true;
+    }
+
     pub fn insert_lines(&mut self, n: usize) {
-        if is_between(self.c.y, self.scroll_top, self.scroll_bot) {
+        if is_between(self.c.y, self.scroll_top, self.scroll_bot) && self.in_lr_margins() {
             self.scroll_down(self.c.y, n);
+            self.c.x = self.scroll_left;
         }
     }
 
     pub fn delete_lines(&mut self, n: usize) {
-        if is_between(self.c.y, self.scroll_top, self.scroll_bot) {
-            self.scroll_up(self.c.y, n);
+        if is_between(self.c.y, self.scroll_top, self.scroll_bot) && self.in_lr_margins() {
+            self.scroll_up_lines(self.c.y, n, false);
+            self.c.x = self.scroll_left;
         }
     }
 
     // move to a y pos that is not derived from a previous y pos
     pub fn move_ato(&mut self, x: usize, y: usize) {
-        let mut y = y;
+        let (mut x, mut y) = (x, y);
         if self.mode.contains(TermMode::ORIGIN) {
+            x += self.scroll_left;
             y += self.scroll_top;
         }
         self.move_to(x, y);
     }
 
     pub fn move_to(&mut self, x: usize, y: usize) {
-        self.c.x = cmp::min(x, self.cols - 1);
         if self.mode.contains(TermMode::ORIGIN) {
+            self.c.x = limit(x, self.scroll_left, self.scroll_right);
             self.c.y = limit(y, self.scroll_top, self.scroll_bot);
         } else {
+            self.c.x = cmp::min(x, self.cols - 1);
             self.c.y = cmp::min(y, self.rows - 1);
         }
         self.c.wrap_next = false;
     }
 
+    // Chars are shifted within the right margin, nothing happens with the
+    // cursor outside of the margins.
     pub fn insert_blanks(&mut self, n: usize) {
-        let (x, y) = (self.c.x, self.c.y);
-        let n = cmp::min(n, self.cols - x);
+        if self.in_lr_margins() {
+            self.insert_cells(self.c.x, self.c.y, n);
+        }
+    }
+
+    pub fn delete_chars(&mut self, n: usize) {
+        if self.in_lr_margins() {
+            self.delete_cells(self.c.x, self.c.y, n);
+        }
+    }
+
+    // DECIC, insert blank columns at the cursor in the scroll region.
+    pub fn insert_columns(&mut self, n: usize) {
+        if self.in_lr_margins() && is_between(self.c.y, self.scroll_top, self.scroll_bot) {
+            for y in self.scroll_top..=self.scroll_bot {
+                self.insert_cells(self.c.x, y, n);
+            }
+        }
+    }
+
+    // DECDC, delete columns at the cursor in the scroll region.
+    pub fn delete_columns(&mut self, n: usize) {
+        if self.in_lr_margins() && is_between(self.c.y, self.scroll_top, self.scroll_bot) {
+            for y in self.scroll_top..=self.scroll_bot {
+                self.delete_cells(self.c.x, y, n);
+            }
+        }
+    }
+
+    // Scroll the columns of the scroll region n columns to the left if
+    // n > 0, or to the right if n < 0.
+    pub fn scroll_columns(&mut self, n: i32) {
+        let x = self.scroll_left;
+        for y in self.scroll_top..=self.scroll_bot {
+            if n > 0 {
+                self.delete_cells(x, y, n as usize);
+            } else if n < 0 {
+                self.insert_cells(x, y, n.unsigned_abs() as usize);
+            }
+        }
+    }
 
-        let source = x..self.cols - n;
+    // Shift the cells from x to the right margin n cells right.
+    fn insert_cells(&mut self, x: usize, y: usize, n: usize) {
+        let end = self.scroll_right + 1;
+        let n = cmp::min(n, end - x);
+
+        self.split_wide(x, y);
+        if n < end - x {
+            self.split_wide(end - n, y);
+        }
+        if end < self.cols {
+            self.split_wide(end - 1, y);
+        }
+        let source = x..end - n;
         let dest = x + n;
         self.lines[y].copy_within(source, dest);
         self.clear_region(x..x + n, y..=y);
     }
 
-    pub fn delete_chars(&mut self, n: usize) {
-        let (x, y, cols) = (self.c.x, self.c.y, self.cols);
-        let n = cmp::min(n, cols - x);
+    // Shift the cells from x + n to the right margin n cells left.
+    fn delete_cells(&mut self, x: usize, y: usize, n: usize) {
+        let end = self.scroll_right + 1;
+        let n = cmp::min(n, end - x);
 
-        self.lines[y].copy_within(x + n..cols, x);
-        self.clear_region(cols - n..cols, y..=y);
+        self.split_wide(x, y);
+        self.split_wide(x + n - 1, y);
+        if end < self.cols {
+            self.split_wide(end - 1, y);
+        }
+        self.lines[y].copy_within(x + n..end, x);
+        self.clear_region(end - n..end, y..=y);
     }
 
     pub fn put_tabs(&mut self, n: i32) {
@@ -355,11 +950,34 @@ impl Term {
 
     pub fn put_char(&mut self, c: char) {
         let width = UnicodeWidthChar::width(c).unwrap_or(0);
+
+        // zero-width chars, and chars following a ZWJ, join the cluster of
+        // the previous cell
+        if let Some((x, y)) = self.prev_cell() {
+            let cluster = self.lines[y][x].cluster;
+            if width == 0 || self.clusters.get(cluster).ends_with('\u{200d}') {
+                if self.clusters.needs_gc() {
+                    self.collect_clusters();
+                }
+                self.lines[y][x].cluster = self.clusters.push(cluster, c);
+                self.dirty[y] = true;
+                if self.is_screen_selected(x, y) {
+                    self.clear_selection();
+                }
+                return;
+            }
+        }
         if width == 0 {
             return;
         }
 
-        let cols = self.cols;
+        // lines wrapped at the right margin are not joined
+        let cols = self.line_end();
+        let wrap = if cols == self.cols {
+            GlyphAttr::WRAP
+        } else {
+            GlyphAttr::empty()
+        };
 
         if self.c.wrap_next {
             let y = self.c.y;
@@ -367,7 +985,7 @@ impl Term {
             self.lines[y][cols - 1]
                 .prop
                 .attr
-                .insert(GlyphAttr::WRAP);
+                .insert(wrap);
             self.new_line(true);
             self.c.wrap_next = false;
         }
@@ -377,20 +995,45 @@ impl Term {
         }
 
         if self.c.x + width > cols {
+            // a wide char does not fit, pad the line and wrap
+            if self.mode.contains(TermMode::WRAP) {
+                let (x, y) = (self.c.x, self.c.y);
+                self.split_wide(x, y);
+                let g = &mut self.lines[y][x];
+                g.c = ' ';
+                g.cluster = 0;
+                g.prop = self.prop;
+                g.prop.attr.insert(GlyphAttr::DUMMY | wrap);
+                self.dirty[y] = true;
+            }
             self.new_line(true);
         }
 
-        if self.is_selected(self.c.x, self.c.y) {
+        if self.is_screen_selected(self.c.x, self.c.y)
+            || self.is_screen_selected(self.c.x + width - 1, self.c.y)
+        {
             self.clear_selection();
         }
 
         // x, y may have updated.
         let (x, y) = (self.c.x, self.c.y);
         self.dirty[y] = true;
+        for x2 in x..x + width {
+            self.split_wide(x2, y);
+        }
         self.lines[y][x].prop = self.prop;
         self.lines[y][x].c = c;
-        for x2 in x + 1..x + width {
-            self.lines[y][x2].prop.attr.insert(GlyphAttr::DUMMY);
+        self.lines[y][x].cluster = 0;
+        self.lines[y][x].image = None;
+        if width > 1 {
+            self.lines[y][x].prop.attr.insert(GlyphAttr::WIDE);
+            for x2 in x + 1..x + width {
+                self.lines[y][x2].c = ' ';
+                self.lines[y][x2].cluster = 0;
+                self.lines[y][x2].image = None;
+                self.lines[y][x2].prop = self.prop;
+                self.lines[y][x2].prop.attr.insert(GlyphAttr::DUMMY);
+            }
         }
 
         self.c.x += width;
@@ -399,23 +1042,184 @@ impl Term {
                 self.c.x -= width;
                 self.c.wrap_next = true;
             } else {
-                self.c.x = 0;
+                self.c.x = self.line_start();
+            }
+        }
+    }
+
+    // Get the cell of the char written last, before the cursor.
+    fn prev_cell(&self) -> Option<(usize, usize)> {
+        let (x, y) = (self.c.x, self.c.y);
+        let x = if self.c.wrap_next { x } else { x.checked_sub(1)? };
+        let line = &self.lines[y];
+        if Self::is_wide_dummy(line, x) {
+            Some((x - 1, y))
+        } else if line[x].prop.attr.contains(GlyphAttr::DUMMY) {
+            None
+        } else {
+            Some((x, y))
+        }
+    }
+
+    // Break up the wide char that the cell at x, y belongs to, so that the
+    // cell can be overwritten.  The other half of the char is blanked.
+    fn split_wide(&mut self, x: usize, y: usize) {
+        let line = &mut self.lines[y];
+        let attr = line[x].prop.attr;
+        if attr.contains(GlyphAttr::WIDE) && x + 1 < line.len() {
+            line[x + 1].c = ' ';
+            line[x + 1].prop.attr.remove(GlyphAttr::DUMMY);
+        } else if attr.contains(GlyphAttr::DUMMY)
+            && x > 0
+            && line[x - 1].prop.attr.contains(GlyphAttr::WIDE)
+        {
+            line[x - 1].c = ' ';
+            line[x - 1].cluster = 0;
+            line[x - 1].prop.attr.remove(GlyphAttr::WIDE);
+        }
+        line[x].prop.attr.remove(GlyphAttr::WIDE | GlyphAttr::DUMMY);
+    }
+
+    // Draw the image over the cells from the cursor, scrolling as needed
+    // and moving the cursor to the line below.  Without scroll the image
+    // is drawn from the top left, clipped by the screen.
+    pub fn put_image(&mut self, image: Image, cursor: ImageCursor) -> u32 {
+        self.collect_images();
+        let (cols, rows) = (image.cols, image.rows);
+        let id = self.images.add(image);
+
+        let scroll = matches!(cursor, ImageCursor::NextLine | ImageCursor::AfterImage);
+        let (x0, y0) = match cursor {
+            ImageCursor::Home => (0, 0),
+            _ => (self.c.x, self.c.y),
+        };
+        for row in 0..rows {
+            if scroll && row > 0 {
+                self.new_line(false);
+            }
+            let y = if scroll { self.c.y } else { y0 + row };
+            if y >= self.rows {
+                break;
+            }
+
+            self.dirty[y] = true;
+            for x in x0..cmp::min(x0 + cols, self.cols) {
+                if self.is_screen_selected(x, y) {
+                    self.clear_selection();
+                }
+                self.split_wide(x, y);
+                let g = &mut self.lines[y][x];
+                g.c = ' ';
+                g.cluster = 0;
+                g.prop = self.prop;
+                g.image = Some(ImageCell {
+                    id,
+                    col: (x - x0) as u16,
+                    row: row as u16,
+                });
+            }
+        }
+
+        match cursor {
+            ImageCursor::NextLine => self.new_line(true),
+            ImageCursor::AfterImage if x0 + cols >= self.cols => self.new_line(true),
+            ImageCursor::AfterImage => self.move_to(x0 + cols, self.c.y),
+            _ => (),
+        }
+        id
+    }
+
+    // The image drawn in the screen cell at x, y.
+    pub fn image_at(&self, x: usize, y: usize) -> Option<ImageCell> {
+        self.lines[y][x].image
+    }
+
+    // Remove the images from all cells, including the history.
+    pub fn clear_images(&mut self, ids: &HashSet<u32>) {
+        if ids.is_empty() {
+            return;
+        }
+        let cells = self
+            .history
+            .iter_mut()
+            .chain(self.lines.iter_mut())
+            .chain(self.alt_lines.iter_mut())
+            .flatten();
+        for g in cells {
+            if g.image.is_some_and(|cell| ids.contains(&cell.id)) {
+                g.image = None;
             }
         }
+        self.set_dirty(0..self.rows, true);
+        self.collect_images();
+    }
+
+    pub fn get_image(&self, id: u32) -> Option<&Image> {
+        self.images.get(id)
+    }
+
+    // Ids of the images dropped, so that their resources can be freed.
+    pub fn take_freed_images(&mut self) -> Vec<u32> {
+        self.images.take_freed()
+    }
+
+    // Drop the images no cell refers to.
+    fn collect_images(&mut self) {
+        if self.images.is_empty() {
+            return;
+        }
+        let used = self
+            .history
+            .iter()
+            .chain(self.lines.iter())
+            .chain(self.alt_lines.iter())
+            .flatten()
+            .filter_map(|g| g.image.map(|cell| cell.id))
+            .collect::<HashSet<_>>();
+        self.images.retain(&used);
+    }
+
+    // Drop the clusters no cell refers to.
+    fn collect_clusters(&mut self) {
+        let used = self
+            .history
+            .iter()
+            .chain(self.lines.iter())
+            .chain(self.alt_lines.iter())
+            .flatten()
+            .map(|g| g.cluster)
+            .collect::<HashSet<_>>();
+        self.clusters.retain(&used);
     }
 
     pub fn put_string(&mut self, string: String) {
         string.chars().for_each(|c| self.put_char(c));
     }
 
+    // Save the cursor along with the attributes, the charsets and the
+    // origin and wrap modes, separately for each screen.
     pub fn save_cursor(&mut self) {
-        self.saved_c = Some(self.c);
+        self.saved_c = Some(SavedCursor {
+            c: self.c,
+            prop: self.prop,
+            charset: self.charset,
+            mode: self.mode & SAVED_MODES,
+        });
     }
 
     pub fn load_cursor(&mut self) {
-        if let Some(c) = self.saved_c {
-            self.c = c;
+        if let Some(s) = self.saved_c {
+            self.prop = s.prop;
+            self.charset = s.charset;
+            self.mode = (self.mode - SAVED_MODES) | s.mode;
+            self.c = s.c;
             self.move_to(self.c.x, self.c.y);
+        } else {
+            // nothing saved restores the power on state
+            self.prop.reset();
+            self.charset = CharsetTable::new();
+            self.mode -= TermMode::ORIGIN;
+            self.move_to(0, 0);
         }
     }
 
@@ -448,25 +1252,39 @@ impl Term {
         self.sel.oe.y = self.sel.ob.y;
 
         self.sel.mode = mode;
+        self.sel.sel_type = SelType::Regular;
         self.sel.empty = self.sel.mode == SnapMode::None;
         self.normalize_selection();
     }
 
-    pub fn extend_selection(&mut self, x: usize, y: usize) {
+    pub fn extend_selection(&mut self, x: usize, y: usize, sel_type: SelType) {
         self.clear_selection();
 
         self.sel.oe.x = cmp::min(x, self.cols - 1);
         self.sel.oe.y = cmp::min(y, self.rows - 1);
+        self.sel.sel_type = sel_type;
 
         self.sel.empty = false;
         self.normalize_selection();
     }
 
     pub fn is_selected(&self, x: usize, y: usize) -> bool {
-        !self.sel.empty
-            && is_between(y, self.sel.nb.y, self.sel.ne.y)
-            && (y != self.sel.nb.y || x >= self.sel.nb.x)
-            && (y != self.sel.ne.y || x <= self.sel.ne.x)
+        if self.sel.empty || !is_between(y, self.sel.nb.y, self.sel.ne.y) {
+            return false;
+        }
+        match self.sel.sel_type {
+            SelType::Regular => {
+                (y != self.sel.nb.y || x >= self.sel.nb.x)
+                    && (y != self.sel.ne.y || x <= self.sel.ne.x)
+            }
+            SelType::Rectangular => is_between(x, self.sel.nb.x, self.sel.ne.x),
+        }
+    }
+
+    // x, y are in screen coordinates.
+    fn is_screen_selected(&self, x: usize, y: usize) -> bool {
+        let y = y + self.scroll_offset;
+        y < self.rows && self.is_selected(x, y)
     }
 
     pub fn clear_selection(&mut self) {
@@ -481,14 +1299,15 @@ impl Term {
 
         let mut string = String::new();
 
+        let rect = self.sel.sel_type == SelType::Rectangular;
         for y in self.sel.nb.y..=self.sel.ne.y {
-            let start = if y == self.sel.nb.y {
+            let start = if y == self.sel.nb.y || rect {
                 self.sel.nb.x
             } else {
                 0
             };
 
-            let end = if y == self.sel.ne.y {
+            let end = if y == self.sel.ne.y || rect {
                 self.sel.ne.x
             } else {
                 self.cols - 1
@@ -496,10 +1315,19 @@ impl Term {
 
             let text_end = cmp::min(end + 1, self.text_len(y));
             for x in start..text_end {
-                string.push(self.lines[y][x].c);
+                let g = self.view_line(y)[x];
+                if !g.prop.attr.contains(GlyphAttr::DUMMY) {
+                    string.push(g.c);
+                    string.push_str(self.clusters.get(g.cluster));
+                }
             }
 
-            if end == self.cols - 1 && !self.is_wrap_line(y) {
+            if rect {
+                // one line per row of the rectangle
+                if y < self.sel.ne.y {
+                    string.push('\n');
+                }
+            } else if end == self.cols - 1 && !self.is_wrap_line(y) {
                 string.push('\n');
             }
         }
@@ -509,6 +1337,7 @@ impl Term {
 
     pub fn swap_screen(&mut self, alt_screen: bool) {
         if self.is_alt_screen != alt_screen {
+            self.reset_view();
             self.is_alt_screen = alt_screen;
             mem::swap(&mut self.saved_c, &mut self.alt_saved_c);
             mem::swap(&mut self.lines, &mut self.alt_lines);
@@ -516,10 +1345,75 @@ impl Term {
         }
     }
 
+    pub fn is_alt_screen(&self) -> bool {
+        self.is_alt_screen
+    }
+
+    pub fn is_scrolled(&self) -> bool {
+        self.scroll_offset > 0
+    }
+
+    // Scroll the view n lines into the history if n > 0, or back
+    // towards the screen if n < 0.
+    pub fn scroll_view(&mut self, n: i32) {
+        if self.is_alt_screen {
+            return;
+        }
+
+        let offset = self.scroll_offset as i32 + n;
+        let offset = limit(offset, 0, self.history.len() as i32) as usize;
+        if offset != self.scroll_offset {
+            if !self.sel.empty {
+                self.clear_selection();
+            }
+            self.scroll_offset = offset;
+            self.set_dirty(0..self.rows, true);
+        }
+    }
+
+    pub fn reset_view(&mut self) {
+        self.scroll_view(-(self.scroll_offset as i32));
+    }
+
+    pub fn clear_history(&mut self) {
+        self.reset_view();
+        self.history.clear();
+    }
+
+    // Move the top n lines of the screen into the history.
+    fn push_history(&mut self, n: usize) {
+        for y in 0..n {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            // keep the view on the same content, unless its top line was
+            // dropped from the history
+            if self.scroll_offset > 0 {
+                self.scroll_offset = cmp::min(self.scroll_offset + 1, HISTORY_SIZE);
+            }
+            self.history.push_back(self.lines[y].clone());
+        }
+        if self.scroll_offset > 0 {
+            self.set_dirty(0..self.rows, true);
+        }
+    }
+
+    fn view_line(&self, y: usize) -> &Vec<Glyph> {
+        if y < self.scroll_offset {
+            &self.history[self.history.len() - self.scroll_offset + y]
+        } else {
+            &self.lines[y - self.scroll_offset]
+        }
+    }
+
     fn normalize_selection(&mut self) {
         let (mut nb, mut ne) = sort_pair(self.sel.ob, self.sel.oe);
+        if self.sel.sel_type == SelType::Rectangular {
+            (nb.x, ne.x) = sort_pair(self.sel.ob.x, self.sel.oe.x);
+        }
 
         match self.sel.mode {
+            SnapMode::None if self.sel.sel_type == SelType::Rectangular => (),
             SnapMode::None => {
                 let end = self.text_len(nb.y);
                 if nb.x > end {
@@ -545,6 +1439,14 @@ impl Term {
             }
         }
 
+        // select both halves of wide chars
+        if Self::is_wide_dummy(self.view_line(nb.y), nb.x) {
+            nb.x -= 1;
+        }
+        if ne.x + 1 < self.cols && Self::is_wide_dummy(self.view_line(ne.y), ne.x + 1) {
+            ne.x += 1;
+        }
+
         self.set_dirty(nb.y..=ne.y, true);
         self.sel.nb = nb;
         self.sel.ne = ne;
@@ -555,6 +1457,12 @@ impl Term {
             return;
         }
 
+        // the selection is relative to the view, not the screen
+        if self.scroll_offset > 0 {
+            self.clear_selection();
+            return;
+        }
+
         if !is_between(self.sel.ob.y, orig, self.scroll_bot)
             || !is_between(self.sel.ob.y, orig, self.scroll_bot)
         {
@@ -582,7 +1490,7 @@ impl Term {
         }
         if p.y > 0 {
             let p = Point::new(self.cols - 1, p.y - 1);
-            if self.lines[p.y][p.x].prop.attr.contains(GlyphAttr::WRAP) {
+            if self.view_line(p.y)[p.x].prop.attr.contains(GlyphAttr::WRAP) {
                 return Some(p);
             }
         }
@@ -594,7 +1502,7 @@ impl Term {
             return Some(Point::new(p.x + 1, p.y));
         }
         if p.y < self.rows - 1
-            && self.lines[p.y][p.x].prop.attr.contains(GlyphAttr::WRAP)
+            && self.view_line(p.y)[p.x].prop.attr.contains(GlyphAttr::WRAP)
         {
             return Some(Point::new(0, p.y + 1));
         }
@@ -605,12 +1513,12 @@ impl Term {
     where
         F: Fn(&Self, &Point) -> Option<Point>,
     {
-        let c = self.lines[point.y][point.x].c;
+        let c = self.char_at(&point);
         let delim = is_delim(c);
 
         let mut point = point;
         while let Some(next_p) = f(self, &point) {
-            let next_c = self.lines[next_p.y][next_p.x].c;
+            let next_c = self.char_at(&next_p);
             if next_c != c && (delim || is_delim(next_c)) {
                 break;
             }
@@ -619,21 +1527,281 @@ impl Term {
         point
     }
 
+    fn char_at(&self, p: &Point) -> char {
+        if Self::is_wide_dummy(self.view_line(p.y), p.x) {
+            self.view_line(p.y)[p.x - 1].c
+        } else {
+            self.view_line(p.y)[p.x].c
+        }
+    }
+
+    // whether the cell at x is the right half of a wide char
+    fn is_wide_dummy(line: &[Glyph], x: usize) -> bool {
+        x > 0
+            && line[x].prop.attr.contains(GlyphAttr::DUMMY)
+            && line[x - 1].prop.attr.contains(GlyphAttr::WIDE)
+    }
+
     fn text_len(&self, y: usize) -> usize {
         let mut x = self.cols;
         if self.is_wrap_line(y) {
             return x;
         }
-        while x > 0 && self.lines[y][x - 1].c == ' ' {
+        while x > 0 && self.view_line(y)[x - 1].c == ' ' {
             x -= 1
         }
         x
     }
 
     fn is_wrap_line(&self, y: usize) -> bool {
-        self.lines[y][self.cols - 1]
+        self.view_line(y)[self.cols - 1]
             .prop
             .attr
             .contains(GlyphAttr::WRAP)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::charset::{Charset, CharsetIndex};
+
+    fn term_with_text(cols: usize, rows: usize, text: &[&str]) -> Term {
+        let mut term = Term::new(cols, rows).unwrap();
+        for (y, line) in text.iter().enumerate() {
+            term.move_to(0, y);
+            term.put_string(line.to_string());
+        }
+        term
+    }
+
+    fn screen_text(term: &Term) -> Vec<String> {
+        term.lines
+            .iter()
+            .map(|line| line.iter().map(|g| g.c).collect::<String>())
+            .collect()
+    }
+
+    #[test]
+    fn scroll_whole_region_with_margins() {
+        let mut term = term_with_text(6, 3, &["abcdef", "ghijkl", "mnopqr"]);
+        term.set_mode(TermMode::LRMARGIN, true);
+        term.set_lr_margins(1, 4);
+
+        term.scroll_up(0, 999);
+        assert_eq!(screen_text(&term), ["a    f", "g    l", "m    r"]);
+
+        let mut term = term_with_text(6, 3, &["abcdef", "ghijkl", "mnopqr"]);
+        term.set_mode(TermMode::LRMARGIN, true);
+        term.set_lr_margins(1, 4);
+        term.move_to(2, 0);
+        term.delete_lines(999);
+        assert_eq!(screen_text(&term), ["a    f", "g    l", "m    r"]);
+    }
+
+    #[test]
+    fn reflow_rejoins_wrapped_lines() {
+        let mut term = term_with_text(5, 3, &["abcdefgh"]);
+        assert_eq!(screen_text(&term), ["abcde", "fgh  ", "     "]);
+        assert_eq!((term.c.x, term.c.y), (3, 1));
+
+        term.resize(10, 3);
+        assert_eq!(screen_text(&term), ["abcdefgh  ", "          ", "          "]);
+        assert_eq!((term.c.x, term.c.y), (8, 0));
+
+        term.resize(4, 3);
+        assert_eq!(screen_text(&term), ["abcd", "efgh", "    "]);
+        assert!(term.lines[0][3].prop.attr.contains(GlyphAttr::WRAP));
+        // the cell under the cursor is kept
+        assert!(term.lines[1][3].prop.attr.contains(GlyphAttr::WRAP));
+        assert_eq!((term.c.x, term.c.y), (0, 2));
+    }
+
+    #[test]
+    fn reflow_cursor_waiting_to_wrap() {
+        let mut term = term_with_text(5, 3, &["abcde"]);
+        assert_eq!((term.c.x, term.c.y, term.c.wrap_next), (4, 0, true));
+
+        term.resize(10, 3);
+        assert_eq!((term.c.x, term.c.y, term.c.wrap_next), (5, 0, false));
+    }
+
+    #[test]
+    fn reflow_keeps_hard_line_breaks() {
+        let mut term = term_with_text(5, 3, &["ab", "cd"]);
+        term.resize(10, 3);
+        assert_eq!(screen_text(&term), ["ab        ", "cd        ", "          "]);
+    }
+
+    #[test]
+    fn reflow_wide_char_at_edge() {
+        let mut term = term_with_text(5, 3, &["abcd\u{5b57}"]);
+        assert_eq!(screen_text(&term), ["abcd ", "\u{5b57}    ", "     "]);
+        assert!(term.lines[0][4].prop.attr.contains(GlyphAttr::DUMMY | GlyphAttr::WRAP));
+
+        // the padding is dropped when the char fits
+        term.resize(6, 3);
+        assert_eq!(screen_text(&term), ["abcd\u{5b57} ", "      ", "      "]);
+        assert!(term.lines[0][4].prop.attr.contains(GlyphAttr::WIDE));
+        assert!(Term::is_wide_dummy(&term.lines[0], 5));
+
+        // and added again when it does not
+        term.resize(5, 3);
+        assert_eq!(screen_text(&term), ["abcd ", "\u{5b57}    ", "     "]);
+        assert!(term.lines[1][0].prop.attr.contains(GlyphAttr::WIDE));
+    }
+
+    #[test]
+    fn reflow_moves_lines_into_history() {
+        let text: Vec<String> = (0..6).map(|i| i.to_string()).collect();
+        let text: Vec<&str> = text.iter().map(|s| s.as_str()).collect();
+        let mut term = term_with_text(3, 6, &text);
+
+        term.resize(3, 4);
+        assert_eq!(term.history.len(), 2);
+        assert_eq!(screen_text(&term), ["2  ", "3  ", "4  ", "5  "]);
+        assert_eq!((term.c.x, term.c.y), (1, 3));
+
+        // the history fills the screen again when it grows
+        term.resize(3, 5);
+        assert_eq!(term.history.len(), 1);
+        assert_eq!(screen_text(&term), ["1  ", "2  ", "3  ", "4  ", "5  "]);
+        assert_eq!((term.c.x, term.c.y), (1, 4));
+    }
+
+    #[test]
+    fn shrink_with_saved_cursor_above() {
+        let mut term = term_with_text(10, 6, &["0", "1", "2", "3", "4", "5"]);
+        term.move_to(0, 0);
+        term.save_cursor();
+        term.move_to(1, 5);
+
+        term.resize(10, 3);
+        assert_eq!(screen_text(&term), ["3         ", "4         ", "5         "]);
+        assert_eq!((term.c.x, term.c.y), (1, 2));
+        term.load_cursor();
+        assert_eq!((term.c.x, term.c.y), (0, 0));
+    }
+
+    #[test]
+    fn set_lr_margins() {
+        let mut term = term_with_text(6, 3, &[]);
+        term.set_mode(TermMode::LRMARGIN, true);
+        term.move_to(3, 2);
+        term.set_lr_margins(4, 1);
+        assert_eq!((term.scroll_left, term.scroll_right), (0, 5));
+        assert_eq!((term.c.x, term.c.y), (3, 2));
+
+        term.set_lr_margins(1, 999);
+        assert_eq!((term.scroll_left, term.scroll_right), (1, 5));
+        assert_eq!((term.c.x, term.c.y), (0, 0));
+    }
+
+    #[test]
+    fn scroll_part_of_region_with_margins() {
+        let mut term = term_with_text(6, 3, &["abcdef", "ghijkl", "mnopqr"]);
+        term.set_mode(TermMode::LRMARGIN, true);
+        term.set_lr_margins(1, 4);
+
+        term.scroll_up(0, 1);
+        assert_eq!(screen_text(&term), ["ahijkf", "gnopql", "m    r"]);
+        term.scroll_down(0, 2);
+        assert_eq!(screen_text(&term), ["a    f", "g    l", "mhijkr"]);
+    }
+
+    #[test]
+    fn load_cursor_with_nothing_saved() {
+        let mut term = term_with_text(6, 3, &[]);
+        term.set_scroll(1, 2);
+        term.set_mode(TermMode::ORIGIN, true);
+        term.charset.setup(CharsetIndex::G0, Charset::Graphic0);
+        term.prop.fg = 1;
+        term.prop.attr |= GlyphAttr::BOLD;
+        term.move_to(3, 1);
+
+        term.load_cursor();
+        assert_eq!((term.c.x, term.c.y), (0, 0));
+        assert!(!term.mode.contains(TermMode::ORIGIN));
+        assert_eq!(term.charset.map('q'), 'q');
+        assert_eq!(term.prop.fg, FG_COLOR);
+        assert!(!term.prop.attr.contains(GlyphAttr::BOLD));
+    }
+
+    // push the numbers of range into the history, one line each
+    fn push_lines(term: &mut Term, range: std::ops::Range<usize>) {
+        for i in range {
+            term.move_to(0, 0);
+            term.put_string(format!("{:<4}", i));
+            term.scroll_up(0, 1);
+        }
+    }
+
+    fn view_text(term: &Term) -> Vec<String> {
+        (0..term.rows)
+            .map(|y| term.view_line(y).iter().map(|g| g.c).collect::<String>())
+            .collect()
+    }
+
+    #[test]
+    fn scroll_view_into_history() {
+        let mut term = term_with_text(4, 2, &[]);
+        push_lines(&mut term, 0..5);
+        term.move_to(0, 0);
+        term.put_string("top".to_string());
+        assert_eq!(view_text(&term), ["top ", "    "]);
+
+        term.scroll_view(2);
+        assert_eq!(view_text(&term), ["3   ", "4   "]);
+        term.scroll_view(-1);
+        assert_eq!(view_text(&term), ["4   ", "top "]);
+        term.scroll_view(99);
+        assert_eq!(view_text(&term), ["0   ", "1   "]);
+        term.reset_view();
+        assert_eq!(view_text(&term), ["top ", "    "]);
+    }
+
+    #[test]
+    fn history_drops_the_oldest_lines() {
+        let mut term = term_with_text(4, 2, &[]);
+        push_lines(&mut term, 0..HISTORY_SIZE + 10);
+        assert_eq!(term.history.len(), HISTORY_SIZE);
+        term.scroll_view(HISTORY_SIZE as i32);
+        assert_eq!(view_text(&term), ["10  ", "11  "]);
+    }
+
+    #[test]
+    fn scrolled_view_stays_on_its_lines() {
+        let mut term = term_with_text(4, 2, &[]);
+        push_lines(&mut term, 0..10);
+        term.scroll_view(5);
+        push_lines(&mut term, 10..12);
+        assert_eq!(view_text(&term), ["5   ", "6   "]);
+
+        // also when the history is full
+        push_lines(&mut term, 12..HISTORY_SIZE + 10);
+        term.reset_view();
+        term.scroll_view(5);
+        assert_eq!(view_text(&term), ["2005", "2006"]);
+        push_lines(&mut term, 0..3);
+        assert_eq!(view_text(&term), ["2005", "2006"]);
+
+        // the top of a full history moves with it
+        term.scroll_view(HISTORY_SIZE as i32);
+        push_lines(&mut term, 0..1);
+        assert_eq!(term.scroll_offset, HISTORY_SIZE);
+        assert_eq!(view_text(&term)[0], "14  ");
+    }
+
+    #[test]
+    fn delete_lines_keeps_history() {
+        let mut term = term_with_text(3, 3, &["abc", "def", "ghi"]);
+        term.move_to(0, 0);
+        term.delete_lines(1);
+        assert_eq!(screen_text(&term), ["def", "ghi", "   "]);
+        assert!(term.history.is_empty());
+
+        term.scroll_up(0, 1);
+        assert_eq!(screen_text(&term), ["ghi", "   ", "   "]);
+        assert_eq!(term.history.len(), 1);
+    }
+}
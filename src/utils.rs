@@ -86,3 +86,130 @@ pub fn parse_geometry(s: &str) -> Result<(usize, usize, usize, usize)> {
     let rows = fields[1].parse::<usize>()?;
     Ok((cols, rows, xoff, yoff))
 }
+
+const BASE64_DIGITS: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(buf: &[u8]) -> String {
+    let mut string = String::with_capacity(buf.len().div_ceil(3) * 4);
+
+    for chunk in buf.chunks(3) {
+        let mut n = 0u32;
+        for (i, &b) in chunk.iter().enumerate() {
+            n |= (b as u32) << (16 - 8 * i);
+        }
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let d = (n >> (18 - 6 * i)) & 0x3F;
+                string.push(BASE64_DIGITS[d as usize] as char);
+            } else {
+                string.push('=');
+            }
+        }
+    }
+
+    string
+}
+
+// Padding is optional and whitespace is skipped.
+pub fn base64_decode(buf: &[u8]) -> Result<Vec<u8>> {
+    let mut v = Vec::with_capacity(buf.len() / 4 * 3);
+    let mut n = 0u32;
+    let mut bits = 0;
+
+    for &b in buf {
+        let d = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => return Err(anyhow!("invalid base64 char {:#x}", b)),
+        };
+        n = (n << 6) | d as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            v.push((n >> bits) as u8);
+        }
+    }
+
+    Ok(v)
+}
+
+pub fn hex_encode(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+pub fn hex_decode(buf: &[u8]) -> Result<Vec<u8>> {
+    if !buf.len().is_multiple_of(2) {
+        return Err(anyhow!("odd length hex string"));
+    }
+    // from_str_radix would take a sign
+    if let Some(&b) = buf.iter().find(|b| !b.is_ascii_hexdigit()) {
+        return Err(anyhow!("invalid hex char {:#x}", b));
+    }
+    buf.chunks(2)
+        .map(|pair| {
+            let s = std::str::from_utf8(pair)?;
+            Ok(u8::from_str_radix(s, 16)?)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert_eq!(base64_encode(&[0xFB, 0xFF]), "+/8=");
+
+        assert_eq!(base64_decode(b"Zg==").unwrap(), b"f");
+        assert_eq!(base64_decode(b"Zm8=").unwrap(), b"fo");
+        assert_eq!(base64_decode(b"Zm9vYg==").unwrap(), b"foob");
+        // padding is optional
+        assert_eq!(base64_decode(b"Zg").unwrap(), b"f");
+        assert_eq!(base64_decode(b"Zm8").unwrap(), b"fo");
+        assert_eq!(base64_decode(b"+/8=").unwrap(), [0xFB, 0xFF]);
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        let data = (0..=255).collect::<Vec<u8>>();
+        assert_eq!(base64_decode(base64_encode(&data).as_bytes()).unwrap(), data);
+    }
+
+    #[test]
+    fn base64_invalid_chars() {
+        assert_eq!(base64_decode(b"Zm9v\r\nYg ==").unwrap(), b"foob");
+        assert!(base64_decode(b"Zm9v!").is_err());
+        assert!(base64_decode(b"Zm-v").is_err());
+        assert!(base64_decode("Zm9\u{e9}".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(hex_encode(b""), "");
+        assert_eq!(hex_encode(&[0x00, 0x1b, 0xff]), "001BFF");
+        assert_eq!(hex_decode(b"001BFF").unwrap(), [0x00, 0x1b, 0xff]);
+        assert_eq!(hex_decode(b"1bff").unwrap(), [0x1b, 0xff]);
+        assert_eq!(hex_decode(b"").unwrap(), []);
+    }
+
+    #[test]
+    fn hex_invalid() {
+        assert!(hex_decode(b"1").is_err());
+        assert!(hex_decode(b"ABC").is_err());
+        assert!(hex_decode(b"0G").is_err());
+        assert!(hex_decode(b"+1").is_err());
+        assert!(hex_decode(b"-1").is_err());
+    }
+}
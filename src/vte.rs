@@ -8,39 +8,157 @@ use crate::color::{
     FG_COLOR, FG_COLOR_NAME,
     CURSOR_COLOR, CURSOR_COLOR_NAME,
 };
+use crate::config::{ClipboardPolicy, Config};
 use crate::cursor::CursorMode;
-use crate::glyph::GlyphAttr;
+use crate::glyph::{GlyphAttr, GlyphProp, UnderlineStyle};
+use crate::image;
+use crate::keymap::KeyFlags;
+use crate::kitty::Kitty;
 use crate::pty::Pty;
-use crate::term::{Term, TermMode};
+use crate::sixel::{SixelParser, SIXEL_COLORS, SIXEL_HEIGHT_MAX, SIXEL_WIDTH_MAX};
+use crate::term::{ImageCursor, Term, TermMode};
+use crate::utils::{base64_decode, hex_decode, hex_encode};
 use crate::win::{Win, WinMode};
 
+use std::cmp;
 use std::iter;
 
 use vte::{Params, ParamsIter, Parser, Perform};
 
 const NAME: &str = env!("CARGO_PKG_NAME");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
-const VTIDEN: &[u8] = b"\x1B[?6c";
+// VT220 with sixel graphics (4), ANSI color (22) and rectangular editing
+// (28)
+const VTIDEN: &str = "\x1B[?62;4;22;28";
+// DA2 terminal type, VT220
+const DA2_TYPE: usize = 1;
+// DA3 unit id
+const DA3_UNIT_ID: &str = "00000000";
+
+// max size of a DCS string, longer strings are dropped
+const DCS_SIZE_MAX: usize = 64 * 1024;
+
+// terminfo capabilities reported by XTGETTCAP
+const TERMCAPS: &[(&str, &str)] = &[
+    ("TN", "xterm"),
+    ("name", "xterm"),
+    ("Co", "256"),
+    ("colors", "256"),
+    ("Ms", "\x1b]52;%p1%s;%p2%s\x07"),
+    ("Ss", "\x1b[%p1%d q"),
+    ("Se", "\x1b[0 q"),
+];
+
+// max size of an APC string, longer strings are dropped
+const APC_SIZE_MAX: usize = 1024 * 1024;
+
+// DCS string being received, it is dispatched when terminated
+struct Dcs {
+    params: Vec<u16>,
+    intermediates: Vec<u8>,
+    action: char,
+    data: Vec<u8>,
+    overflow: bool,
+    // sixel data is decoded as it arrives instead of buffered
+    sixel: Option<SixelParser>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ApcState {
+    Ground,
+    Escape,
+    String,
+    StringEscape,
+}
+
+// The vte parser ignores APC strings, so they are picked out of the input
+// as it is fed to the parser.  An ESC ends any sequence in the parser as
+// well, so both agree on where an APC string starts and ends.
+struct Apc {
+    state: ApcState,
+    data: Vec<u8>,
+    overflow: bool,
+}
+
+impl Apc {
+    fn new() -> Self {
+        Apc {
+            state: ApcState::Ground,
+            data: Vec::new(),
+            overflow: false,
+        }
+    }
+
+    // Return the APC string terminated by byte.
+    fn advance(&mut self, byte: u8) -> Option<Vec<u8>> {
+        match (self.state, byte) {
+            (ApcState::String, 0x1B) => self.state = ApcState::StringEscape,
+            (ApcState::String, 0x18 | 0x1A) => self.state = ApcState::Ground,
+            (ApcState::String, _) => {
+                if self.data.len() < APC_SIZE_MAX {
+                    self.data.push(byte);
+                } else {
+                    self.overflow = true;
+                }
+            }
+            (ApcState::StringEscape, b'\\') => {
+                self.state = ApcState::Ground;
+                if self.overflow {
+                    println!("apc too long, dropped");
+                    return None;
+                }
+                return Some(std::mem::take(&mut self.data));
+            }
+            (_, 0x1B) => self.state = ApcState::Escape,
+            (ApcState::Escape | ApcState::StringEscape, b'_') => {
+                self.state = ApcState::String;
+                self.data.clear();
+                self.overflow = false;
+            }
+            _ => self.state = ApcState::Ground,
+        }
+        None
+    }
+}
 
 pub struct Vte {
     parser: Parser,
     last_c: Option<char>,
+    dcs: Option<Dcs>,
+    apc: Apc,
+    kitty: Kitty,
+    clipboard: ClipboardPolicy,
 }
 
 impl Vte {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         Vte {
             parser: Parser::new(),
             last_c: None,
+            dcs: None,
+            apc: Apc::new(),
+            kitty: Kitty::new(config.kitty_files),
+            clipboard: config.clipboard,
         }
     }
 
     pub fn process_input(
         &mut self, buf: &[u8], win: &mut Win, term: &mut Term, pty: &mut Pty
     ) {
-        let mut performer = Performer::new(win, term, pty, self.last_c.take());
-        self.parser.advance(&mut performer, buf);
+        let mut performer = Performer::new(
+            win, term, pty, &mut self.kitty, self.clipboard, self.last_c.take(), self.dcs.take()
+        );
+        let mut start = 0;
+        for (i, &byte) in buf.iter().enumerate() {
+            if let Some(apc) = self.apc.advance(byte) {
+                self.parser.advance(&mut performer, &buf[start..=i]);
+                start = i + 1;
+                performer.apc_dispatch(&apc);
+            }
+        }
+        self.parser.advance(&mut performer, &buf[start..]);
         self.last_c = performer.last_c.take();
+        self.dcs = performer.dcs.take();
     }
 }
 
@@ -48,7 +166,10 @@ struct Performer<'a> {
     win: &'a mut Win,
     term: &'a mut Term,
     pty: &'a mut Pty,
+    kitty: &'a mut Kitty,
+    clipboard: ClipboardPolicy,
     last_c: Option<char>,
+    dcs: Option<Dcs>,
 }
 
 impl<'a> Performer<'a> {
@@ -56,77 +177,115 @@ impl<'a> Performer<'a> {
         win: &'a mut Win,
         term: &'a mut Term,
         pty: &'a mut Pty,
+        kitty: &'a mut Kitty,
+        clipboard: ClipboardPolicy,
         last_c: Option<char>,
+        dcs: Option<Dcs>,
     ) -> Self {
         Self {
             win,
             term,
             pty,
+            kitty,
+            clipboard,
             last_c,
+            dcs,
         }
     }
 
-    fn defcolor(params: &mut ParamsIter) -> usize {
-        fn getcolor(params: &mut ParamsIter) -> Option<u16> {
-            if let Some(col) = params.next() {
-                if col.len() == 1 {
-                    Some(col[0])
-                } else {
-                    None
-                }
-            } else {
-                None
+    // Parse the color of SGR 38, 48 and 58, either from the subparameters of
+    // param as in "38:2::r:g:b", or from the following params as in
+    // "38;2;r;g;b".  The implementation defined and transparent colors are
+    // the default color.
+    fn defcolor(param: &[u16], params: &mut ParamsIter, default: usize) -> Option<usize> {
+        // number of color components of each color space
+        fn components(space: u16) -> usize {
+            match space {
+                2 | 3 => 3,
+                4 => 4,
+                5 => 1,
+                _ => 0,
             }
         }
 
-        let mut color: usize = 0;
-        if let Some(op) = params.next() {
-            if op.is_empty() {
-                return 0;
+        let args: Vec<u16> = if param.len() > 1 {
+            let mut args = param[1..].to_vec();
+            // the color space id of "38:2:Pi:r:g:b" is ignored
+            if (2..=4).contains(&args[0]) && args.len() > components(args[0]) + 1 {
+                args.remove(1);
             }
-            match op[0] {
-                // direct color in RGB space
-                2 => {
-                    let r = getcolor(params);
-                    let g = getcolor(params);
-                    let b = getcolor(params);
-                    if let (Some(r), Some(g), Some(b)) = (r, g, b) {
-                        if r > 255 || g > 255 || b > 255 {
-                            println!("bad rgb color ({},{},{})\n", r, g, b);
-                        } else {
-                            color = 1 << 24
-                                | (r as usize) << 16
-                                | (g as usize) << 8
-                                | b as usize;
-                        }
-                    } else {
-                        println!("Incorrect number of rgb parameters");
-                    }
+            args
+        } else {
+            let space = params.next().map(|p| p[0]);
+            let n = space.map_or(0, components);
+            space
+                .into_iter()
+                .chain(params.by_ref().take(n).map(|p| p[0]))
+                .collect()
+        };
+        let rgb = |r: u16, g: u16, b: u16| {
+            Some(1 << 24 | (r as usize) << 16 | (g as usize) << 8 | b as usize)
+        };
+
+        match *args.as_slice() {
+            // direct color in RGB space
+            [2, r, g, b, ..] => {
+                if r > 255 || g > 255 || b > 255 {
+                    println!("bad rgb color ({},{},{})", r, g, b);
+                    return None;
                 }
-                // indexed color
-                5 => {
-                    if let Some(c) = getcolor(params) {
-                        if c <= 255 {
-                            color = c as usize;
-                        } else {
-                            println!("Incorrect color index: {}", c);
-                        }
-                    } else {
-                        println!("Missing color index parameter");
-                    }
+                rgb(r, g, b)
+            }
+            [2, ..] => {
+                println!("Incorrect number of rgb parameters");
+                None
+            }
+            // direct color in CMY space
+            [3, c, m, y, ..] => {
+                if c > 255 || m > 255 || y > 255 {
+                    println!("bad cmy color ({},{},{})", c, m, y);
+                    return None;
                 }
-                0 => {} // implemented defined (only foreground)
-                1 => {} // transparent
-                3 => {} // direct color in CMY space
-                4 => {} // direct color in CMYK space
-                x => {
-                    println!("gfx attr {} unknown\n", x);
+                rgb(255 - c, 255 - m, 255 - y)
+            }
+            [3, ..] => {
+                println!("Incorrect number of cmy parameters");
+                None
+            }
+            // direct color in CMYK space
+            [4, c, m, y, k, ..] => {
+                if c > 255 || m > 255 || y > 255 || k > 255 {
+                    println!("bad cmyk color ({},{},{},{})", c, m, y, k);
+                    return None;
                 }
+                let v = |x: u16| ((255 - x) as u32 * (255 - k) as u32 / 255) as u16;
+                rgb(v(c), v(m), v(y))
+            }
+            [4, ..] => {
+                println!("Incorrect number of cmyk parameters");
+                None
+            }
+            // indexed color
+            [5, c, ..] if c <= 255 => Some(c as usize),
+            [5, c, ..] => {
+                println!("Incorrect color index: {}", c);
+                None
+            }
+            [5] => {
+                println!("Missing color index parameter");
+                None
+            }
+            [0, ..] => Some(default), // implemented defined
+            [1, ..] => Some(default), // transparent
+            [x, ..] => {
+                println!("gfx attr {} unknown", x);
+                None
+            }
+            [] => {
+                println!("unknown color glyph attr");
+                None
             }
-        } else {
-            println!("unknown color glyph attr");
         }
-        color
     }
 
     fn set_glyph_attr(&mut self, params: &Params) {
@@ -144,24 +303,44 @@ impl<'a> Performer<'a> {
                 1 => prop.attr.insert(GlyphAttr::BOLD),
                 2 => prop.attr.insert(GlyphAttr::FAINT),
                 3 => prop.attr.insert(GlyphAttr::ITALIC),
-                4 => prop.attr.insert(GlyphAttr::UNDERLINE),
-                5 | 6 => prop.attr.insert(GlyphAttr::BLINK),
+                // "4:n" sets the underline style
+                4 => prop.attr.set_underline(param.get(1).copied().unwrap_or(1)),
+                5 => prop.attr.insert(GlyphAttr::BLINK),
+                6 => prop.attr.insert(GlyphAttr::RAPID_BLINK),
                 7 => prop.attr.insert(GlyphAttr::REVERSE),
                 8 => prop.attr.insert(GlyphAttr::INVISIBLE),
                 9 => prop.attr.insert(GlyphAttr::STRUCK),
+                21 => prop.attr.set_underline(2),
                 22 => prop.attr.remove(GlyphAttr::BOLD_FAINT),
                 23 => prop.attr.remove(GlyphAttr::ITALIC),
-                24 => prop.attr.remove(GlyphAttr::UNDERLINE),
-                25 => prop.attr.remove(GlyphAttr::BLINK),
+                24 => prop.attr.set_underline(0),
+                25 => prop.attr.remove(GlyphAttr::BLINK | GlyphAttr::RAPID_BLINK),
                 27 => prop.attr.remove(GlyphAttr::REVERSE),
                 28 => prop.attr.remove(GlyphAttr::INVISIBLE),
                 29 => prop.attr.remove(GlyphAttr::STRUCK),
                 30..=37 => prop.fg = (param[0] - 30) as usize,
-                38 => prop.fg = Self::defcolor(&mut params),
+                38 => {
+                    if let Some(color) = Self::defcolor(param, &mut params, FG_COLOR) {
+                        prop.fg = color;
+                    }
+                }
                 39 => prop.reset_fg(),
                 40..=47 => prop.bg = (param[0] - 40) as usize,
-                48 => prop.bg = Self::defcolor(&mut params),
+                48 => {
+                    if let Some(color) = Self::defcolor(param, &mut params, BG_COLOR) {
+                        prop.bg = color;
+                    }
+                }
                 49 => prop.reset_bg(),
+                53 => prop.attr.insert(GlyphAttr::OVERLINE),
+                55 => prop.attr.remove(GlyphAttr::OVERLINE),
+                58 => {
+                    // the default underline color follows fg
+                    if let Some(color) = Self::defcolor(param, &mut params, FG_COLOR) {
+                        prop.ul = Some(color).filter(|&c| c != FG_COLOR);
+                    }
+                }
+                59 => prop.ul = None,
                 90..=97 => prop.fg = (param[0] - 90 + 8) as usize,
                 100..=107 => prop.bg = (param[0] - 100 + 8) as usize,
                 _ => println!("unknown glyph attr {}", param[0]),
@@ -223,6 +402,14 @@ impl<'a> Performer<'a> {
                     // 1006: extended reporting mode
                     1006 => self.win.set_mode(WinMode::MOUSESGR, val),
                     1034 => self.win.set_mode(WinMode::EIGHT_BIT, val),
+                    // 2004: bracketed paste
+                    2004 => self.win.set_mode(WinMode::BRCKTPASTE, val),
+                    // DECSDM -- Sixel Display Mode
+                    80 => self.term.set_mode(TermMode::SIXEL_DISPLAY, val),
+                    // DECLRMM -- Left Right Margin Mode
+                    69 => self.term.set_mode(TermMode::LRMARGIN, val),
+                    // 2026: synchronized output
+                    2026 => self.win.set_sync(val),
                     // 1048: save/load cursor position
                     1048 => {
                         if val {
@@ -272,6 +459,64 @@ impl<'a> Performer<'a> {
         }
     }
 
+    // Get the state of a mode known by set_mode, None if not recognized.
+    fn get_mode(&self, private: bool, mode: u16) -> Option<bool> {
+        let win = &*self.win;
+        let term = &*self.term;
+
+        let val = if private {
+            match mode {
+                1 => win.get_mode(WinMode::APPCURSOR),
+                5 => win.get_mode(WinMode::REVERSE),
+                6 => term.get_mode(TermMode::ORIGIN),
+                7 => term.get_mode(TermMode::WRAP),
+                25 => !win.get_mode(WinMode::HIDE),
+                9 => win.get_mode(WinMode::MOUSEX10),
+                1000 => win.get_mode(WinMode::MOUSEBTN),
+                1002 => win.get_mode(WinMode::MOUSEMOTION),
+                1003 => win.get_mode(WinMode::MOUSEMANY),
+                1004 => win.get_mode(WinMode::FOCUS),
+                1006 => win.get_mode(WinMode::MOUSESGR),
+                1034 => win.get_mode(WinMode::EIGHT_BIT),
+                2004 => win.get_mode(WinMode::BRCKTPASTE),
+                2026 => win.get_mode(WinMode::SYNC),
+                80 => term.get_mode(TermMode::SIXEL_DISPLAY),
+                69 => term.get_mode(TermMode::LRMARGIN),
+                // saving the cursor has no state
+                1048 => false,
+                47 | 1047 | 1049 => term.is_alt_screen(),
+                _ => return None,
+            }
+        } else {
+            match mode {
+                4 => term.get_mode(TermMode::INSERT),
+                12 => !win.get_mode(WinMode::ECHO),
+                20 => term.get_mode(TermMode::CRLF),
+                _ => return None,
+            }
+        };
+        Some(val)
+    }
+
+    // DECRQM -- Request Mode, answered with DECRPM
+    fn report_mode(&mut self, intermediate: Option<&u8>, mode: u16) {
+        let private = match intermediate {
+            Some(b'?') => true,
+            None => false,
+            _ => return,
+        };
+        // 0: not recognized, 1: set, 2: reset
+        let state = match self.get_mode(private, mode) {
+            Some(true) => 1,
+            Some(false) => 2,
+            None => 0,
+        };
+        let s = format!(
+            "\x1B[{}{};{}$y", if private { "?" } else { "" }, mode, state
+        );
+        self.pty.write(s.as_bytes());
+    }
+
     fn send_color_osc(
         &mut self, idx: usize, leader: &str, bell_terminated: bool
     ) {
@@ -288,6 +533,418 @@ impl<'a> Performer<'a> {
         }
         self.pty.write(&v);
     }
+
+    // OSC 52 -- Manipulate selection data
+    fn osc52(&mut self, params: &[&[u8]], bell_terminated: bool) {
+        let (targets, data) = match (params.get(1), params.get(2)) {
+            (Some(targets), Some(data)) => (targets, data),
+            _ => {
+                println!("OSC 52, missing parameters");
+                return;
+            }
+        };
+        // only the first of the selections is used, default to clipboard
+        let clipboard = !matches!(targets.first(), Some(b'p') | Some(b's'));
+
+        if *data == b"?" {
+            if self.clipboard >= ClipboardPolicy::AllowRead {
+                self.win.query_selection(clipboard, bell_terminated);
+            }
+            return;
+        }
+        if self.clipboard < ClipboardPolicy::AllowWrite {
+            return;
+        }
+        match base64_decode(data) {
+            Ok(text) => {
+                let text = String::from_utf8_lossy(&text).into_owned();
+                self.win.set_selection(clipboard, text);
+            }
+            Err(err) => println!("OSC 52 error: {}", err),
+        }
+    }
+
+    // OSC 1337 -- iTerm2 inline image, File=args:data
+    fn iterm_file(&mut self, params: &[&[u8]]) {
+        // the args are separated by ';' as well
+        let params = params[1..].join(&b';');
+        let (args, data) = match params.iter().position(|&b| b == b':') {
+            Some(i) if params.starts_with(b"File=") => (&params[5..i], &params[i + 1..]),
+            _ => {
+                println!("OSC 1337, unknown command");
+                return;
+            }
+        };
+
+        let (cw, ch) = self.win.cell_size();
+        let (mut cols, mut rows) = (None, None);
+        let mut keep_aspect = true;
+        let mut inline = false;
+        let mut stay = false;
+        for arg in args.split(|&b| b == b';') {
+            let arg = String::from_utf8_lossy(arg);
+            match arg.split_once('=') {
+                Some(("width", v)) => cols = image_cells(v, cw, self.term.cols),
+                Some(("height", v)) => rows = image_cells(v, ch, self.term.rows),
+                Some(("preserveAspectRatio", v)) => keep_aspect = v != "0",
+                Some(("inline", v)) => inline = v == "1",
+                Some(("doNotMoveCursor", v)) => stay = v == "1",
+                _ => (),
+            }
+        }
+        if !inline {
+            println!("OSC 1337, file download not supported");
+            return;
+        }
+
+        let image = match base64_decode(data).and_then(|data| image::decode(&data)) {
+            Ok(image) => image,
+            Err(err) => {
+                println!("OSC 1337 error: {}", err);
+                return;
+            }
+        };
+        // images are shrunk to the screen width unless sized
+        if cols.is_none() && rows.is_none() && image.width > self.term.cols * cw {
+            cols = Some(self.term.cols);
+        }
+        let cursor = if stay {
+            ImageCursor::Stay
+        } else {
+            ImageCursor::AfterImage
+        };
+        let image = image.fit_cells(cols, rows, keep_aspect, cw, ch);
+        self.term.put_image(image, cursor);
+    }
+
+    fn dcs_dispatch(&mut self, dcs: Dcs) {
+        if let Some(sixel) = dcs.sixel {
+            self.put_sixel(sixel);
+            return;
+        }
+
+        match (dcs.action, dcs.intermediates.as_slice()) {
+            // DECRQSS -- Request Selection or Setting
+            ('q', b"$") => self.decrqss(&dcs.data),
+            // XTGETTCAP -- Request Termcap/Terminfo String
+            ('q', b"+") => self.xtgettcap(&dcs.data),
+            // DECUDK -- User Defined Keys
+            ('|', b"") => self.decudk(&dcs.params, &dcs.data),
+            _ => println!(
+                "unknown dcs {:?} {:?} {}", dcs.intermediates, dcs.params, dcs.action
+            ),
+        }
+    }
+
+    fn apc_dispatch(&mut self, data: &[u8]) {
+        match data.first() {
+            // kitty graphics protocol
+            Some(b'G') => {
+                let (cw, ch) = self.win.cell_size();
+                if let Some(reply) = self.kitty.command(&data[1..], self.term, cw, ch) {
+                    self.pty.write(&reply);
+                }
+            }
+            _ => println!("unknown apc {:?}", String::from_utf8_lossy(data)),
+        }
+    }
+
+    fn put_sixel(&mut self, sixel: SixelParser) {
+        if let Some(image) = sixel.finish() {
+            let (cw, ch) = self.win.cell_size();
+            let cursor = if self.term.get_mode(TermMode::SIXEL_DISPLAY) {
+                ImageCursor::Home
+            } else {
+                ImageCursor::NextLine
+            };
+            self.term.put_image(image.at_pixel_size(cw, ch), cursor);
+        }
+    }
+
+    // Rectangular area operations, the rectangle is given by the first 4
+    // params, after the char for DECFRA.
+    fn rect_area(&mut self, action: char, params: &Params) {
+        let args: Vec<u16> = params.iter().map(|p| p[0]).collect();
+        let arg = |i: usize| args.get(i).copied().unwrap_or(0);
+        let term = &mut *self.term;
+
+        let first = if action == 'x' { 1 } else { 0 };
+        let rect = match term.area(arg(first), arg(first + 1), arg(first + 2), arg(first + 3)) {
+            Some(rect) => rect,
+            None => return,
+        };
+        // the attributes of DECCARA and DECRARA, default to 0
+        let attrs = match args.get(4..) {
+            Some(attrs) if !attrs.is_empty() => attrs,
+            _ => &[0],
+        };
+        let underline = GlyphAttr::UNDERLINE | GlyphAttr::UNDERLINE_STYLE;
+
+        match action {
+            // DECFRA -- Fill Rectangular Area
+            'x' => {
+                let c = char::from_u32(arg(0) as u32)
+                    .filter(|&c| matches!(c as u32, 32..=126 | 160..=255));
+                if let Some(c) = c {
+                    term.fill_rect(rect, c);
+                }
+            }
+            // DECERA -- Erase Rectangular Area
+            'z' => term.clear_region(rect.left..=rect.right, rect.top..=rect.bot),
+            // DECCRA -- Copy Rectangular Area, pages are ignored
+            'v' => {
+                if let Some(dst) = term.area(arg(5), arg(6), 0, 0) {
+                    term.copy_rect(rect, dst.top, dst.left);
+                }
+            }
+            // DECCARA -- Change Attributes in Rectangular Area
+            'r' => {
+                let all = GlyphAttr::BOLD | underline | GlyphAttr::BLINK | GlyphAttr::REVERSE;
+                let none = GlyphAttr::empty();
+                let (mut set, mut clear) = (none, none);
+                for &n in attrs {
+                    let (on, off) = match n {
+                        0 => (none, all),
+                        1 => (GlyphAttr::BOLD, none),
+                        4 => (GlyphAttr::UNDERLINE, GlyphAttr::UNDERLINE_STYLE),
+                        5 => (GlyphAttr::BLINK, none),
+                        7 => (GlyphAttr::REVERSE, none),
+                        22 => (none, GlyphAttr::BOLD),
+                        24 => (none, underline),
+                        25 => (none, GlyphAttr::BLINK),
+                        27 => (none, GlyphAttr::REVERSE),
+                        v => {
+                            println!("unknown DECCARA attr {}", v);
+                            continue;
+                        }
+                    };
+                    set = (set - off) | on;
+                    clear = (clear - on) | off;
+                }
+                term.change_attrs(rect, |attr| *attr = (*attr - clear) | set);
+            }
+            // DECRARA -- Reverse Attributes in Rectangular Area
+            't' => {
+                let mut toggle = GlyphAttr::empty();
+                for &n in attrs {
+                    toggle |= match n {
+                        0 => {
+                            GlyphAttr::BOLD
+                                | GlyphAttr::UNDERLINE
+                                | GlyphAttr::BLINK
+                                | GlyphAttr::REVERSE
+                        }
+                        1 => GlyphAttr::BOLD,
+                        4 => GlyphAttr::UNDERLINE,
+                        5 => GlyphAttr::BLINK,
+                        7 => GlyphAttr::REVERSE,
+                        v => {
+                            println!("unknown DECRARA attr {}", v);
+                            continue;
+                        }
+                    };
+                }
+                term.change_attrs(rect, |attr| {
+                    attr.toggle(toggle);
+                    if !attr.contains(GlyphAttr::UNDERLINE) {
+                        attr.remove(GlyphAttr::UNDERLINE_STYLE);
+                    }
+                });
+            }
+            _ => (),
+        }
+    }
+
+    // XTSMGRAPHICS -- Set or request graphics attribute
+    fn graphics_attr(&mut self, item: u16, action: u16) {
+        let (cw, ch) = self.win.cell_size();
+        let width = cmp::min(self.term.cols * cw, SIXEL_WIDTH_MAX);
+        let height = cmp::min(self.term.rows * ch, SIXEL_HEIGHT_MAX);
+
+        // status 0: success, 1: error in item, 2: error in action,
+        // 3: failure
+        let s = match (item, action) {
+            // number of color registers: read, reset, read maximum
+            (1, 1) | (1, 2) | (1, 4) => format!("1;0;{}", SIXEL_COLORS),
+            // sixel geometry: read, reset
+            (2, 1) | (2, 2) => format!("2;0;{};{}", width, height),
+            // read maximum
+            (2, 4) => format!("2;0;{};{}", SIXEL_WIDTH_MAX, SIXEL_HEIGHT_MAX),
+            (1, 3) | (2, 3) => format!("{};3;0", item),
+            (1, _) | (2, _) => format!("{};2;0", item),
+            _ => format!("{};1;0", item),
+        };
+        self.pty.write(format!("\x1B[?{}S", s).as_bytes());
+    }
+
+    fn decrqss(&mut self, data: &[u8]) {
+        let term = &*self.term;
+        let setting = match data {
+            // SGR
+            b"m" => Some(format!("{}m", sgr_string(&term.prop))),
+            // DECSTBM
+            b"r" => Some(format!("{};{}r", term.scroll_top + 1, term.scroll_bot + 1)),
+            // DECSCA
+            b"\"q" => {
+                let protected = term.prop.attr.contains(GlyphAttr::PROTECTED);
+                Some(format!("{}\"q", protected as usize))
+            }
+            // DECSACE
+            b"*x" => Some(format!("{}*x", 1 + term.get_mode(TermMode::RECT_EXTENT) as usize)),
+            // DECSLRM
+            b"s" => Some(format!("{};{}s", term.scroll_left + 1, term.scroll_right + 1)),
+            // DECSCUSR
+            b" q" => {
+                let style = match term.c.mode {
+                    CursorMode::Block => 1,
+                    CursorMode::Underline => 3,
+                    CursorMode::Bar => 5,
+                };
+                Some(format!("{} q", style + !term.c.blink as usize))
+            }
+            _ => None,
+        };
+
+        let s = match setting {
+            Some(setting) => format!("\x1bP1$r{}\x1b\\", setting),
+            None => "\x1bP0$r\x1b\\".to_string(),
+        };
+        self.pty.write(s.as_bytes());
+    }
+
+    fn xtgettcap(&mut self, data: &[u8]) {
+        for hex in data.split(|&b| b == b';') {
+            let value = hex_decode(hex).ok().and_then(|name| {
+                TERMCAPS
+                    .iter()
+                    .find(|(cap, _)| cap.as_bytes() == name)
+                    .map(|(_, value)| value)
+            });
+            let hex = String::from_utf8_lossy(hex);
+            let s = match value {
+                Some(value) => format!(
+                    "\x1bP1+r{}={}\x1b\\", hex, hex_encode(value.as_bytes())
+                ),
+                None => format!("\x1bP0+r{}\x1b\\", hex),
+            };
+            self.pty.write(s.as_bytes());
+        }
+    }
+
+    fn decudk(&mut self, params: &[u16], data: &[u8]) {
+        // Pc: 0 to clear all keys first, 1 to only replace the keys given.
+        // Pl, locking the keys, is not supported.
+        if params.first().copied().unwrap_or(0) == 0 {
+            self.win.clear_udk();
+        }
+
+        for def in data.split(|&b| b == b';') {
+            if def.is_empty() {
+                continue;
+            }
+            let mut fields = def.splitn(2, |&b| b == b'/');
+            let key = fields
+                .next()
+                .and_then(|k| std::str::from_utf8(k).ok())
+                .and_then(|k| k.parse::<u16>().ok());
+            let value = fields.next().map(hex_decode);
+            match (key, value) {
+                (Some(key), Some(Ok(value))) => self.win.set_udk(key, value),
+                _ => println!("DECUDK, bad key definition {:?}", def),
+            }
+        }
+    }
+}
+
+// Get the cells of an iTerm2 image size in cells, "Npx" or "N%" of the
+// screen, None for "auto".
+fn image_cells(size: &str, cell: usize, screen: usize) -> Option<usize> {
+    let cells = if let Some(px) = size.strip_suffix("px") {
+        px.parse::<usize>().ok()?.div_ceil(cell)
+    } else if let Some(percent) = size.strip_suffix('%') {
+        percent.parse::<usize>().ok()?.saturating_mul(screen) / 100
+    } else {
+        size.parse::<usize>().ok()?
+    };
+    Some(cells.clamp(1, u16::MAX as usize))
+}
+
+// The DA1 reply, clipboard access (52) is only reported when allowed.
+fn vtiden(clipboard: ClipboardPolicy) -> String {
+    if clipboard == ClipboardPolicy::Deny {
+        format!("{}c", VTIDEN)
+    } else {
+        format!("{};52c", VTIDEN)
+    }
+}
+
+// The version as a number, 1.2.3 is 10203.
+fn version_number() -> usize {
+    VERSION
+        .split('.')
+        .take(3)
+        .map(|v| v.parse::<usize>().unwrap_or(0))
+        .fold(0, |n, v| n * 100 + v)
+}
+
+// Parameters of SGR that sets prop.
+fn sgr_string(prop: &GlyphProp) -> String {
+    let mut params = vec!["0".to_string()];
+
+    const ATTRS: &[(GlyphAttr, u8)] = &[
+        (GlyphAttr::BOLD, 1),
+        (GlyphAttr::FAINT, 2),
+        (GlyphAttr::ITALIC, 3),
+        (GlyphAttr::BLINK, 5),
+        (GlyphAttr::RAPID_BLINK, 6),
+        (GlyphAttr::REVERSE, 7),
+        (GlyphAttr::INVISIBLE, 8),
+        (GlyphAttr::STRUCK, 9),
+        (GlyphAttr::OVERLINE, 53),
+    ];
+    for &(attr, n) in ATTRS {
+        if prop.attr.contains(attr) {
+            params.push(n.to_string());
+        }
+    }
+    let underline = match prop.attr.underline_style() {
+        Some(UnderlineStyle::Single) => "4",
+        Some(UnderlineStyle::Double) => "4:2",
+        Some(UnderlineStyle::Curly) => "4:3",
+        Some(UnderlineStyle::Dotted) => "4:4",
+        Some(UnderlineStyle::Dashed) => "4:5",
+        None => "",
+    };
+    if !underline.is_empty() {
+        params.push(underline.to_string());
+    }
+
+    for (color, default, base) in [(prop.fg, FG_COLOR, 30), (prop.bg, BG_COLOR, 40)] {
+        if color == default {
+            continue;
+        }
+        let s = if color >= 1 << 24 {
+            let (r, g, b) = ((color >> 16) & 0xFF, (color >> 8) & 0xFF, color & 0xFF);
+            format!("{};2;{};{};{}", base + 8, r, g, b)
+        } else if color < 8 {
+            format!("{}", base + color)
+        } else if color < 16 {
+            format!("{}", base + 60 + color - 8)
+        } else {
+            format!("{};5;{}", base + 8, color)
+        };
+        params.push(s);
+    }
+    match prop.ul {
+        Some(color) if color >= 1 << 24 => {
+            let (r, g, b) = ((color >> 16) & 0xFF, (color >> 8) & 0xFF, color & 0xFF);
+            params.push(format!("58:2::{}:{}:{}", r, g, b));
+        }
+        Some(color) => params.push(format!("58:5:{}", color)),
+        None => (),
+    }
+
+    params.join(";")
 }
 
 impl<'a> Perform for Performer<'a> {
@@ -297,6 +954,46 @@ impl<'a> Perform for Performer<'a> {
         self.last_c = Some(c);
     }
 
+    fn hook(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        if ignore {
+            println!("invalid dcs intermediates {:?}", intermediates);
+            return;
+        }
+        let sixel = if action == 'q' && intermediates.is_empty() {
+            Some(SixelParser::new())
+        } else {
+            None
+        };
+        self.dcs = Some(Dcs {
+            params: params.iter().map(|param| param[0]).collect(),
+            intermediates: intermediates.to_vec(),
+            action,
+            data: Vec::new(),
+            overflow: false,
+            sixel,
+        });
+    }
+
+    fn put(&mut self, byte: u8) {
+        if let Some(dcs) = &mut self.dcs {
+            if let Some(sixel) = &mut dcs.sixel {
+                sixel.put(byte);
+            } else if dcs.data.len() < DCS_SIZE_MAX {
+                dcs.data.push(byte);
+            } else {
+                dcs.overflow = true;
+            }
+        }
+    }
+
+    fn unhook(&mut self) {
+        match self.dcs.take() {
+            Some(dcs) if dcs.overflow => println!("dcs too long, dropped"),
+            Some(dcs) => self.dcs_dispatch(dcs),
+            None => (),
+        }
+    }
+
     fn execute(&mut self, byte: u8) {
         let win = &mut *self.win;
         let term = &mut *self.term;
@@ -309,7 +1006,7 @@ impl<'a> Perform for Performer<'a> {
             // HT
             0x09 => term.put_tabs(1),
             // CR
-            0x0D => term.move_to(0, term.c.y),
+            0x0D => term.move_to(term.line_start(), term.c.y),
             // LF VT FF
             0x0A | 0x0B | 0x0C => term.new_line(false),
             // SO
@@ -349,10 +1046,12 @@ impl<'a> Perform for Performer<'a> {
                 }
             }
             // DECID -- Identify Terminal
-            (b'Z', None) => self.pty.write(VTIDEN),
+            (b'Z', None) => self.pty.write(vtiden(self.clipboard).as_bytes()),
             // RIS -- Reset to initial state
             (b'c', None) => {
                 win.reset_colors();
+                win.reset_key_flags();
+                self.kitty.reset();
                 term.reset()
                 // FIXME: reset title and etc.
             }
@@ -400,7 +1099,8 @@ impl<'a> Perform for Performer<'a> {
                     self.win.settitle(&String::from_utf8_lossy(title));
                 }
             }
-            b"52" => {} // FIXME
+            b"52" => self.osc52(params, bell_terminated),
+            b"1337" => self.iterm_file(params),
             b"4" => {
                 // color set, color index;spec
                 let mut params = params.iter();
@@ -581,7 +1281,18 @@ impl<'a> Perform for Performer<'a> {
         let term = &mut *self.term;
         let (x, y) = (term.c.x, term.c.y);
 
-        if has_ignored_intermediates || intermediates.len() > 1 {
+        if has_ignored_intermediates || intermediates.len() > 2 {
+            println!("invalid csi intermediates {:?}", intermediates);
+            return;
+        }
+
+        // DECRQM -- Request Mode
+        if let (b'p', [.., b'$']) = (action as u8, intermediates) {
+            let mode = params.iter().next().map_or(0, |p| p[0]);
+            self.report_mode(intermediates.first().filter(|&&b| b != b'$'), mode);
+            return;
+        }
+        if intermediates.len() > 1 {
             println!("invalid csi intermediates {:?}", intermediates);
             return;
         }
@@ -604,6 +1315,10 @@ impl<'a> Perform for Performer<'a> {
         match (action, intermediate) {
             // ICH -- Insert <n> blank char
             ('@', None) => term.insert_blanks(arg0_or(1)),
+            // SL -- Scroll <n> columns left
+            ('@', Some(b' ')) => term.scroll_columns(arg0_or(1) as i32),
+            // SR -- Scroll <n> columns right
+            ('A', Some(b' ')) => term.scroll_columns(-(arg0_or(1) as i32)),
             // CUU -- Cursor <n> Up
             ('A', None) => term.move_to(x, y.saturating_sub(arg0_or(1))),
             // CUD -- Cursor <n> Down
@@ -620,9 +1335,17 @@ impl<'a> Perform for Performer<'a> {
             // CUF -- Cursor <n> Forward | HPR -- Cursor <n> Forward
             ('C', None) | ('a', None) => term.move_to(x + arg0_or(1), y),
             // DA -- Device Attributes
-            ('c', None) if arg0_or(0) == 0 => self.pty.write(VTIDEN),
+            ('c', None) if arg0_or(0) == 0 => self.pty.write(vtiden(self.clipboard).as_bytes()),
             // DA2 -- Secondary Device Attributes
-            ('c', Some(b'>')) if arg0_or(0) == 0 => {} // FIXME
+            ('c', Some(b'>')) if arg0_or(0) == 0 => {
+                let s = format!("\x1B[>{};{};0c", DA2_TYPE, version_number());
+                self.pty.write(s.as_bytes());
+            }
+            // DA3 -- Tertiary Device Attributes
+            ('c', Some(b'=')) if arg0_or(0) == 0 => {
+                let s = format!("\x1BP!|{}\x1B\\", DA3_UNIT_ID);
+                self.pty.write(s.as_bytes());
+            }
             // CUB -- Cursor <n> Backward
             ('D', None) => term.move_to(x.saturating_sub(arg0_or(1)), y),
             // VPA -- Move to <row>
@@ -649,30 +1372,40 @@ impl<'a> Perform for Performer<'a> {
             // CHT -- Cursor Forward Tabulation <n> tab stops
             ('I', None) => term.put_tabs(arg0_or(1) as i32),
             // ED -- Clear screen
-            ('J', None) => match arg0_or(0) {
-                // below
-                0 => {
-                    term.clear_region(x..term.cols, iter::once(y));
-                    term.clear_region(0..term.cols, y + 1..term.rows);
-                }
-                // above
-                1 => {
-                    term.clear_region(0..term.cols, 0..y);
-                    term.clear_region(0..=x, iter::once(y));
+            // DECSED -- Selective erase in display, protected cells are kept
+            ('J', None) | ('J', Some(b'?')) => {
+                let selective = intermediate.is_some();
+                match arg0_or(0) {
+                    // below
+                    0 => {
+                        term.erase_region(x..term.cols, iter::once(y), selective);
+                        term.erase_region(0..term.cols, y + 1..term.rows, selective);
+                    }
+                    // above
+                    1 => {
+                        term.erase_region(0..term.cols, 0..y, selective);
+                        term.erase_region(0..=x, iter::once(y), selective);
+                    }
+                    // all
+                    2 => term.erase_region(0..term.cols, 0..term.rows, selective),
+                    // scrollback history
+                    3 if !selective => term.clear_history(),
+                    v => println!("unknown ED {}", v),
                 }
-                // all
-                2 => term.clear_screen(),
-                v => println!("unknown ED {}", v),
             }
             // EL erase line
-            ('K', None) => match arg0_or(0) {
-                // right
-                0 => term.clear_region(x..term.cols, iter::once(y)),
-                // left
-                1 => term.clear_region(0..=x, iter::once(y)),
-                // all
-                2 => term.clear_region(0..term.cols, iter::once(y)),
-                v => println!("unknown EL {}", v),
+            // DECSEL -- Selective erase in line, protected cells are kept
+            ('K', None) | ('K', Some(b'?')) => {
+                let selective = intermediate.is_some();
+                match arg0_or(0) {
+                    // right
+                    0 => term.erase_region(x..term.cols, iter::once(y), selective),
+                    // left
+                    1 => term.erase_region(0..=x, iter::once(y), selective),
+                    // all
+                    2 => term.erase_region(0..term.cols, iter::once(y), selective),
+                    v => println!("unknown EL {}", v),
+                }
             }
             // IL -- Insert <n> blank lines
             ('L', None) => term.insert_lines(arg0_or(1)),
@@ -682,6 +1415,21 @@ impl<'a> Perform for Performer<'a> {
             ('M', None) => term.delete_lines(arg0_or(1)),
             // SGR -- Terminal attribute (color)
             ('m', None) => self.set_glyph_attr(params),
+            // XTMODKEYS -- Set key modifier options, only modifyOtherKeys
+            ('m', Some(b'>')) => match arg0_or(0) {
+                4 => {
+                    let level = arg1.map_or(0, |p| p[0]);
+                    self.win.set_modify_other_keys(cmp::min(level, 2) as u8);
+                }
+                v => println!("unknown XTMODKEYS {}", v),
+            },
+            // XTQMODKEYS -- Query key modifier options
+            ('m', Some(b'?')) if arg0_or(0) == 4 => {
+                let s = format!("\x1B[>4;{}m", self.win.modify_other_keys());
+                self.pty.write(s.as_bytes());
+            }
+            // XTMODKEYS -- Disable key modifier options
+            ('n', Some(b'>')) if arg0_or(0) == 4 => self.win.set_modify_other_keys(0),
             // DSR Device Status Report
             ('n', None) if arg0_or(0) == 6 =>
                 self.pty.write(format!("\x1B[{};{}R", y + 1, x + 1).as_bytes()),
@@ -696,12 +1444,50 @@ impl<'a> Perform for Performer<'a> {
             }
             // SU -- Scroll <n> line up
             ('S', None) => term.scroll_up(term.scroll_top, arg0_or(1)),
+            // DECSLRM -- Set Left and Right Margins
+            ('s', None) if term.get_mode(TermMode::LRMARGIN) => {
+                let left = arg0_or(1) - 1;
+                let right = arg1_or(term.cols) - 1;
+                term.set_lr_margins(left, right);
+            }
             // DECSC -- Save cursor position (ANSI.SYS)
             ('s', None) => term.save_cursor(),
             // SD -- Scroll <n> line down
             ('T', None) => term.scroll_down(term.scroll_top, arg0_or(1)),
             // DECRC -- Restore cursor position (ANSI.SYS)
             ('u', None) => term.load_cursor(),
+            // kitty keyboard protocol -- push, pop, set and query flags
+            ('u', Some(b'>')) => {
+                let flags = KeyFlags::from_bits_truncate(arg0_or(0) as u16);
+                self.win.push_key_flags(term.is_alt_screen(), flags);
+            }
+            ('u', Some(b'<')) => self.win.pop_key_flags(term.is_alt_screen(), arg0_or(1)),
+            ('u', Some(b'=')) => {
+                let flags = KeyFlags::from_bits_truncate(arg0_or(0) as u16);
+                self.win.set_key_flags(term.is_alt_screen(), flags, arg1_or(1));
+            }
+            ('u', Some(b'?')) => {
+                let flags = self.win.key_flags(term.is_alt_screen());
+                self.pty.write(format!("\x1B[?{}u", flags.bits()).as_bytes());
+            }
+            // DECFRA, DECERA, DECCRA, DECCARA, DECRARA
+            ('x' | 'z' | 'v' | 'r' | 't', Some(b'$')) => self.rect_area(action, params),
+            // DECSCA -- Select Character Protection Attribute
+            ('q', Some(b'"')) => match arg0_or(0) {
+                0 | 2 => term.prop.attr.remove(GlyphAttr::PROTECTED),
+                1 => term.prop.attr.insert(GlyphAttr::PROTECTED),
+                v => println!("unknown DECSCA {}", v),
+            },
+            // DECSACE -- Select Attribute Change Extent
+            ('x', Some(b'*')) => match arg0_or(0) {
+                0 | 1 => term.set_mode(TermMode::RECT_EXTENT, false),
+                2 => term.set_mode(TermMode::RECT_EXTENT, true),
+                v => println!("unknown DECSACE {}", v),
+            },
+            // DECIC -- Insert <n> columns
+            ('}', Some(b'\'')) => term.insert_columns(arg0_or(1)),
+            // DECDC -- Delete <n> columns
+            ('~', Some(b'\'')) => term.delete_columns(arg0_or(1)),
             // ECH -- Erase <n> char
             ('X', None) => term.clear_region(x..x + arg0_or(1), iter::once(y)),
             // CBT -- Cursor Backward Tabulation <n> tab stops
@@ -725,6 +1511,12 @@ impl<'a> Perform for Performer<'a> {
                 }
                 _ => (),
             },
+            // XTSMGRAPHICS -- Set or request graphics attribute
+            ('S', Some(b'?')) => {
+                let item = arg0.map_or(0, |p| p[0]);
+                let action = arg1.map_or(0, |p| p[0]);
+                self.graphics_attr(item, action);
+            }
             // DECSCUSR -- Set Cursor Style
             ('q', Some(b' ')) => match arg0_or(0) {
                 // Blinking block
@@ -765,3 +1557,78 @@ impl<'a> Perform for Performer<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the color of the first param of "CSI params m"
+    fn color(params: &str) -> Option<usize> {
+        struct Sgr(Option<Option<usize>>);
+        impl Perform for Sgr {
+            fn csi_dispatch(&mut self, params: &Params, _: &[u8], _: bool, _: char) {
+                let mut params = params.iter();
+                let param = params.next().unwrap();
+                self.0 = Some(Performer::defcolor(param, &mut params, FG_COLOR));
+            }
+        }
+
+        let mut sgr = Sgr(None);
+        Parser::new().advance(&mut sgr, format!("\x1b[{}m", params).as_bytes());
+        sgr.0.unwrap()
+    }
+
+    #[test]
+    fn defcolor_semicolon_forms() {
+        assert_eq!(color("38;5;100"), Some(100));
+        assert_eq!(color("38;2;1;2;3"), Some(0x1010203));
+        assert_eq!(color("38;2;1;2;300"), None);
+        assert_eq!(color("38;2;1;2"), None);
+    }
+
+    #[test]
+    fn defcolor_colon_forms() {
+        assert_eq!(color("38:5:100"), Some(100));
+        assert_eq!(color("38:5:256"), None);
+        assert_eq!(color("38:2:1:2:3"), Some(0x1010203));
+        // with the color space id, empty or not
+        assert_eq!(color("38:2::1:2:3"), Some(0x1010203));
+        assert_eq!(color("38:2:0:1:2:3"), Some(0x1010203));
+        assert_eq!(color("38:0"), Some(FG_COLOR));
+        assert_eq!(color("38:1"), Some(FG_COLOR));
+    }
+
+    #[test]
+    fn defcolor_cmy_and_cmyk() {
+        assert_eq!(color("38:3:0:255:55"), Some(0x1ff00c8));
+        assert_eq!(color("38:3::0:255:55"), Some(0x1ff00c8));
+        assert_eq!(color("38;3;0;255;55"), Some(0x1ff00c8));
+        assert_eq!(color("38:3:0:256:55"), None);
+        assert_eq!(color("38:4:0:255:55:0"), Some(0x1ff00c8));
+        // black at full key
+        assert_eq!(color("38:4:0:0:0:255"), Some(0x1000000));
+        assert_eq!(color("38:4::0:0:0:51"), Some(0x1cccccc));
+        assert_eq!(color("38;4;0;0;0;51"), Some(0x1cccccc));
+        assert_eq!(color("38:4:0:0:0"), None);
+    }
+
+    #[test]
+    fn da1_reports_clipboard_when_allowed() {
+        assert_eq!(vtiden(ClipboardPolicy::Deny), "\x1b[?62;4;22;28c");
+        assert_eq!(vtiden(ClipboardPolicy::AllowWrite), "\x1b[?62;4;22;28;52c");
+        assert_eq!(vtiden(ClipboardPolicy::AllowRead), "\x1b[?62;4;22;28;52c");
+    }
+
+    #[test]
+    fn iterm2_image_cells() {
+        assert_eq!(image_cells("auto", 10, 80), None);
+        assert_eq!(image_cells("5", 10, 80), Some(5));
+        assert_eq!(image_cells("25px", 10, 80), Some(3));
+        assert_eq!(image_cells("50%", 10, 80), Some(40));
+        // at least one cell
+        assert_eq!(image_cells("0", 10, 80), Some(1));
+        assert_eq!(image_cells("1%", 10, 80), Some(1));
+        assert_eq!(image_cells("99999999", 10, 80), Some(u16::MAX as usize));
+        assert_eq!(image_cells("-1px", 10, 80), None);
+    }
+}
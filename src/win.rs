@@ -1,4 +1,5 @@
 use crate::app::app_exit;
+use crate::cluster;
 use crate::color::{
     BG_COLOR, BG_COLOR_NAME, FG_COLOR_NAME,
     CURSOR_COLOR, CURSOR_COLOR_NAME,
@@ -6,15 +7,22 @@ use crate::color::{
 };
 use crate::cursor::CursorMode;
 use crate::font::Font;
-use crate::glyph::{GlyphAttr, GlyphProp};
-use crate::keymap::map_key;
+use crate::glyph::{GlyphAttr, GlyphProp, UnderlineStyle};
+use crate::image::ImageCell;
+use crate::keymap::{
+    kitty_key, map_key, modify_other_key, udk_key, KeyEvent, KeyFlags,
+};
 use crate::pty::Pty;
 use crate::shortcut::find_shortcut;
 use crate::snap::Snap;
-use crate::term::Term;
-use crate::utils::{epoch_ms, term_decode};
+use crate::term::{SelType, Term};
+use crate::utils::{base64_encode, epoch_ms, term_decode};
 use crate::x11_wrapper as x11;
 
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::ops::Range;
 use std::os::raw::*;
 use std::os::fd::RawFd;
 use std::ptr::null_mut;
@@ -44,9 +52,14 @@ bitflags! {
                              Self::MOUSEX10.bits() |
                              Self::MOUSEMANY.bits());
         const FOCUS       = 1 << 12;
+        const BRCKTPASTE  = 1 << 13;
+        const SYNC        = 1 << 14;
     }
 }
 
+// max depth of the kitty keyboard flags stack
+const KEY_FLAGS_MAX: usize = 16;
+
 // FIXME: this can only be 0 until impemented everywhere.
 // FIXME: auto fix size
 // FIXME: display geometry
@@ -57,19 +70,36 @@ const CURSORTHICKNESS: usize = 2;
 
 const FORCEMOUSEMOD: u32 = x11::ShiftMask;
 
+// bracketed paste markers
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+// modifiers to select a rectangle
+const SELRECTMOD: u32 = x11::ControlMask | x11::Mod1Mask;
+
 const BLINK_PERIOD_MS: i64 = 500;
+const RAPID_BLINK_PERIOD_MS: i64 = BLINK_PERIOD_MS / 4;
+
+// max time drawing is held back by synchronized output
+const SYNC_TIMEOUT_MS: i64 = 150;
 
 pub fn next_blink_timeout() -> i64 {
-    BLINK_PERIOD_MS - epoch_ms() % BLINK_PERIOD_MS
+    blink_timeout(BLINK_PERIOD_MS)
 }
 
-fn blink_hide() -> bool {
-    (epoch_ms() / BLINK_PERIOD_MS) % 2 == 1
+fn blink_timeout(period: i64) -> i64 {
+    period - epoch_ms() % period
+}
+
+fn blink_hide(period: i64) -> bool {
+    (epoch_ms() / period) % 2 == 1
 }
 
 pub struct Win {
     visible: bool,
     mode: WinMode,
+    // when synchronized output started
+    sync_start: i64,
 
     dpy: x11::Display,
     win: x11::Window,
@@ -77,6 +107,9 @@ pub struct Win {
     cmap: x11::Colormap,
     scr: c_int,
     buf: x11::Pixmap,
+    buf_pic: x11::Picture,
+    // pictures of the images in term
+    pictures: HashMap<u32, x11::Picture>,
     gc: x11::GC,
     colors: Vec<x11::XftColor>,
     draw: x11::XftDraw,
@@ -86,13 +119,32 @@ pub struct Win {
     ca: usize,
     cursor_x: usize,
     cursor_y: usize,
+    // rapid blinking text was drawn
+    rapid_blink: bool,
     old_mouse_x: usize,
     old_mouse_y: usize,
     old_mouse_button: u32,
 
     sel_type: x11::Atom,
     sel_snap: Snap,
+    // text owned as PRIMARY and CLIPBOARD
     sel_text: Option<String>,
+    clip_text: Option<String>,
+    clipboard: x11::Atom,
+    // OSC 52 query waiting for the selection: selection, bell terminated
+    sel_query: Option<(x11::Atom, bool)>,
+    sel_query_prop: x11::Atom,
+
+    // user defined keys set by DECUDK
+    udk: HashMap<u16, Vec<u8>>,
+    // kitty keyboard flags stacks of the main and alternate screens
+    key_flags: [Vec<KeyFlags>; 2],
+    // keys whose press was reported, to tell repeats and releases
+    keys_down: HashSet<c_uint>,
+    // xterm modifyOtherKeys level
+    modify_other_keys: u8,
+    // xterm formatOtherKeys
+    format_other_keys: u8,
 
     wm_protocols: x11::Atom,
     wm_delete_window: x11::Atom,
@@ -162,6 +214,7 @@ impl Win {
         attributes.colormap = cmap;
         attributes.background_pixel = colors[BG_COLOR].pixel;
         attributes.event_mask = x11::KEY_PRESS_MASK
+            | x11::KEY_RELEASE_MASK
             | x11::FOCUS_CHANGE_MASK
             | x11::EXPOSURE_MASK
             | x11::VISIBILITY_CHANGE_MASK
@@ -192,6 +245,7 @@ impl Win {
 
         let buf = x11::XCreatePixmap(dpy, win, width, height, depth);
         let draw = x11::XftDrawCreate(dpy, buf, vis, cmap);
+        let buf_pic = x11::XRenderCreatePicture(dpy, buf, vis);
 
         let cursor = x11::XCreateFontCursor(dpy, x11::XC_XTERM);
         x11::XDefineCursor(dpy, win, cursor);
@@ -202,6 +256,8 @@ impl Win {
         x11::XSetWMProtocols(dpy, win, &mut protocols);
 
         let sel_type = x11::XInternAtom(dpy, "UTF8_STRING", x11::False);
+        let clipboard = x11::XInternAtom(dpy, "CLIPBOARD", x11::False);
+        let sel_query_prop = x11::XInternAtom(dpy, "RTERM_SELECTION", x11::False);
 
         x11::XMapWindow(dpy, win);
         x11::XSync(dpy, x11::False);
@@ -210,14 +266,27 @@ impl Win {
         let netwmiconname = x11::XInternAtom(dpy, "_NET_WM_ICON_NAME", x11::False);
 
         let ime = Ime::new(dpy, win);
+        // repeated keys come without releases in between
+        x11::XkbSetDetectableAutoRepeat(dpy, true);
 
         Ok(Win {
             visible: true,
             mode: WinMode::empty(),
+            sync_start: 0,
 
             sel_type,
             sel_snap: Snap::new(),
             sel_text: None,
+            clip_text: None,
+            clipboard,
+            sel_query: None,
+            sel_query_prop,
+
+            udk: HashMap::new(),
+            key_flags: [Vec::new(), Vec::new()],
+            keys_down: HashSet::new(),
+            modify_other_keys: 0,
+            format_other_keys: 0,
 
             dpy,
             win,
@@ -228,6 +297,8 @@ impl Win {
             gc,
             colors,
             buf,
+            buf_pic,
+            pictures: HashMap::new(),
             draw,
             font,
             cw,
@@ -235,6 +306,7 @@ impl Win {
             ca,
             cursor_x: 0,
             cursor_y: 0,
+            rapid_blink: false,
             old_mouse_x: 0,
             old_mouse_y: 0,
             old_mouse_button: 0,
@@ -351,11 +423,112 @@ impl Win {
         self.mode.set(mode, val);
     }
 
+    pub fn get_mode(&self, mode: WinMode) -> bool {
+        self.mode.contains(mode)
+    }
+
+    // Hold back drawing while the application updates the screen.
+    pub fn set_sync(&mut self, val: bool) {
+        if val && !self.mode.contains(WinMode::SYNC) {
+            self.sync_start = epoch_ms();
+        }
+        self.mode.set(WinMode::SYNC, val);
+    }
+
+    // Time until the next draw is needed without new input.
+    pub fn next_timeout(&self) -> i64 {
+        let timeout = if self.rapid_blink {
+            blink_timeout(RAPID_BLINK_PERIOD_MS)
+        } else {
+            next_blink_timeout()
+        };
+        if self.mode.contains(WinMode::SYNC) {
+            let left = self.sync_start + SYNC_TIMEOUT_MS - epoch_ms();
+            cmp::min(timeout, cmp::max(left, 0))
+        } else {
+            timeout
+        }
+    }
+
+    pub fn set_udk(&mut self, key: u16, s: Vec<u8>) {
+        self.udk.insert(key, s);
+    }
+
+    pub fn clear_udk(&mut self) {
+        self.udk.clear();
+    }
+
+    pub fn key_flags(&self, alt: bool) -> KeyFlags {
+        let stack = &self.key_flags[alt as usize];
+        stack.last().copied().unwrap_or(KeyFlags::empty())
+    }
+
+    pub fn push_key_flags(&mut self, alt: bool, flags: KeyFlags) {
+        let stack = &mut self.key_flags[alt as usize];
+        if stack.len() >= KEY_FLAGS_MAX {
+            stack.remove(0);
+        }
+        stack.push(flags);
+    }
+
+    pub fn pop_key_flags(&mut self, alt: bool, n: usize) {
+        let stack = &mut self.key_flags[alt as usize];
+        stack.truncate(stack.len().saturating_sub(n));
+    }
+
+    // Change the current flags: 1 sets, 2 adds and 3 removes them.
+    pub fn set_key_flags(&mut self, alt: bool, flags: KeyFlags, how: usize) {
+        let stack = &mut self.key_flags[alt as usize];
+        if stack.is_empty() {
+            stack.push(KeyFlags::empty());
+        }
+        let current = stack.last_mut().unwrap();
+        match how {
+            1 => *current = flags,
+            2 => *current |= flags,
+            3 => *current &= !flags,
+            _ => println!("unknown key flags mode {}", how),
+        }
+    }
+
+    pub fn reset_key_flags(&mut self) {
+        self.key_flags = [Vec::new(), Vec::new()];
+        self.modify_other_keys = 0;
+    }
+
+    pub fn modify_other_keys(&self) -> u8 {
+        self.modify_other_keys
+    }
+
+    pub fn set_modify_other_keys(&mut self, level: u8) {
+        self.modify_other_keys = level;
+    }
+
+    pub fn set_format_other_keys(&mut self, format: u8) {
+        self.format_other_keys = format;
+    }
+
     pub fn draw(&mut self, term: &mut Term) {
         if !self.visible {
             return;
         }
 
+        if self.mode.contains(WinMode::SYNC) {
+            if epoch_ms() - self.sync_start < SYNC_TIMEOUT_MS {
+                return;
+            }
+            // the application did not end the update in time
+            self.mode.remove(WinMode::SYNC);
+        }
+
+        for id in term.take_freed_images() {
+            if let Some(picture) = self.pictures.remove(&id) {
+                x11::XRenderFreePicture(self.dpy, picture);
+            }
+        }
+
+        // blinking lines are always redrawn, so this is found again
+        self.rapid_blink = false;
         for y in 0..term.rows {
             if term.is_line_dirty(y) {
                 self.draw_line(term, y);
@@ -393,7 +566,7 @@ impl Win {
             match xev_type {
                 x11::EXPOSE => (),
                 x11::MAP_NOTIFY => (),
-                x11::KEY_RELEASE => (),
+                x11::KEY_RELEASE => self.key_release(xev, term, pty),
                 x11::REPARENT_NOTIFY => (),
                 x11::KEY_PRESS => self.key_press(xev, term, pty),
                 x11::CLIENT_MESSAGE => self.client_message(xev),
@@ -418,23 +591,33 @@ impl Win {
         if x >= term.cols || y >= term.rows {
             return;
         }
-        let g = term.get_glyph(x, y);
-        self.draw_cells(&[g.c], g.prop, x * self.cw, y * self.ch);
+        let (x, g) = term.get_cell(x, y);
+        self.draw_cells(&[(g.c, term.cluster(&g))], g.prop, x * self.cw, y * self.ch);
+        self.draw_images(term, x..x + 1, y);
     }
 
     fn draw_cursor(&mut self, term: &Term) {
         self.undraw_cursor(term);
 
-        if self.mode.contains(WinMode::HIDE) || (term.c.blink && blink_hide()) {
+        if self.mode.contains(WinMode::HIDE)
+            || (term.c.blink && blink_hide(BLINK_PERIOD_MS))
+            || term.is_scrolled()
+        {
             return;
         }
 
-        let (x, y) = (term.c.x, term.c.y);
+        let (x, y) = term.cursor_cell();
+        let g = term.get_glyph_at_cursor();
+        let width = if g.prop.attr.contains(GlyphAttr::WIDE) {
+            self.cw * 2
+        } else {
+            self.cw
+        };
 
         match term.c.mode {
             CursorMode::Block => {
-                let g = term.get_glyph_at_cursor();
-                self.draw_cells(&[g.c], g.prop, x * self.cw, y * self.ch);
+                let cs = [(g.c, term.cluster(&g))];
+                self.draw_cells(&cs, g.prop, x * self.cw, y * self.ch);
             }
             CursorMode::Underline => {
                 let drawcol = if term.is_selected(x, y) {
@@ -447,7 +630,7 @@ impl Win {
                     &drawcol,
                     BORDERPX + x * self.cw,
                     BORDERPX + (y + 1) * self.ch - CURSORTHICKNESS,
-                    self.cw,
+                    width,
                     CURSORTHICKNESS,
                 );
             }
@@ -574,6 +757,38 @@ impl Win {
             return;
         }
 
+        if xev.state & x11::ShiftMask != 0 {
+            if let Some(s) = udk_key(ksym).and_then(|n| self.udk.get(&n)) {
+                let s = s.clone();
+                self.term_write(term, pty, &s);
+                return;
+            }
+        }
+
+        let flags = self.key_flags(term.is_alt_screen());
+        if !flags.is_empty() {
+            let event = if self.keys_down.insert(xev.keycode) {
+                KeyEvent::Press
+            } else {
+                KeyEvent::Repeat
+            };
+            let text = String::from_utf8_lossy(&buf[..len]);
+            let base = x11::XLookupKeysym(xev, 0);
+            let shifted = x11::XLookupKeysym(xev, 1);
+            if let Some(s) = kitty_key(ksym, base, shifted, xev.state, event, &text, flags) {
+                if !s.is_empty() {
+                    self.term_write(term, pty, &s);
+                }
+                return;
+            }
+        }
+
+        let (level, format) = (self.modify_other_keys, self.format_other_keys);
+        if let Some(s) = modify_other_key(ksym, xev.state, &buf[..len], level, format) {
+            self.term_write(term, pty, &s);
+            return;
+        }
+
         if let Some(key) = map_key(ksym, xev.state, &self.mode) {
             self.term_write(term, pty, key);
             return;
@@ -597,6 +812,31 @@ impl Win {
         self.term_write(term, pty, &buf[..len]);
     }
 
+    // Releases are only reported with the kitty keyboard protocol.
+    fn key_release(&mut self, xev: x11::XEvent, term: &mut Term, pty: &mut Pty) {
+        let mut xev = xev;
+        let xev: &mut x11::XKeyEvent = x11::cast_event_mut(&mut xev);
+        if !self.keys_down.remove(&xev.keycode) {
+            return;
+        }
+        let flags = self.key_flags(term.is_alt_screen());
+        if flags.is_empty() {
+            return;
+        }
+
+        let mut buf = [0u8; 64];
+        let (ksym, len) = x11::XLookupString(xev, &mut buf);
+        let text = String::from_utf8_lossy(&buf[..len]);
+        let base = x11::XLookupKeysym(xev, 0);
+        let shifted = x11::XLookupKeysym(xev, 1);
+        let event = KeyEvent::Release;
+        if let Some(s) = kitty_key(ksym, base, shifted, xev.state, event, &text, flags) {
+            if !s.is_empty() {
+                self.term_write(term, pty, &s);
+            }
+        }
+    }
+
     fn client_message(&mut self, xev: x11::XEvent) {
         let xev: &x11::XClientMessageEvent = x11::cast_event(&xev);
         if xev.message_type == self.wm_protocols && xev.format == 32 {
@@ -619,9 +859,11 @@ impl Win {
         let width = term.cols * self.cw;
         let height = term.rows * self.ch;
         let depth = x11::XDefaultDepth(self.dpy, self.scr);
+        x11::XRenderFreePicture(self.dpy, self.buf_pic);
         x11::XFreePixmap(self.dpy, self.buf);
         self.buf = x11::XCreatePixmap(self.dpy, self.win, width, height, depth);
         x11::XftDrawChange(self.draw, self.buf);
+        self.buf_pic = x11::XRenderCreatePicture(self.dpy, self.buf, self.vis);
     }
 
     fn visibility_notify(&mut self, xev: x11::XEvent) {
@@ -633,7 +875,6 @@ impl Win {
         self.visible = false;
     }
 
-    // FIXME: select rectangle
     fn motion_notify(&mut self, xev: x11::XEvent, term: &mut Term, pty: &mut Pty) {
         let xev: &x11::XButtonEvent = x11::cast_event(&xev);
         if self.mode.intersects(WinMode::MOUSE) && xev.state & FORCEMOUSEMOD == 0 {
@@ -641,7 +882,12 @@ impl Win {
             return;
         }
         let (x, y) = self.term_point(xev.x, xev.y);
-        term.extend_selection(x, y);
+        let sel_type = if xev.state & SELRECTMOD == SELRECTMOD {
+            SelType::Rectangular
+        } else {
+            SelType::Regular
+        };
+        term.extend_selection(x, y, sel_type);
     }
 
     fn button_press(&mut self, xev: x11::XEvent, term: &mut Term, pty: &mut Pty) {
@@ -672,6 +918,13 @@ impl Win {
     fn selection_notify(&mut self, xev: x11::XEvent, term: &mut Term, pty: &mut Pty) {
         let xev: &x11::XSelectionEvent = x11::cast_event(&xev);
         if xev.property == 0 {
+            if let Some((selection, bell_terminated)) = self.sel_query {
+                if selection == xev.selection {
+                    // no owner, reply with an empty selection
+                    self.sel_query = None;
+                    self.selection_reply(pty, selection, b"", bell_terminated);
+                }
+            }
             return;
         }
 
@@ -681,6 +934,7 @@ impl Win {
         let mut t = 0;
         let mut format = 0;
         let mut data = null_mut();
+        let mut text = Vec::new();
 
         loop {
             if x11::XGetWindowProperty(
@@ -709,7 +963,7 @@ impl Win {
 
             let len = (nitems * (format as c_ulong)/ 8) as usize;
             let buf = unsafe { slice::from_raw_parts(data, len) };
-            self.term_write(term, pty, buf);
+            text.extend_from_slice(buf);
             x11::XFree(data as *mut _);
 
             if rem == 0 {
@@ -718,15 +972,36 @@ impl Win {
                 ofs += (nitems * (format as c_ulong) / 32) as c_long;
             }
         }
-    }
 
-    fn selection_request(&mut self, xev: x11::XEvent) {
-        if self.sel_text.is_none() {
+        if xev.property == self.sel_query_prop {
+            if let Some((selection, bell_terminated)) = self.sel_query.take() {
+                self.selection_reply(pty, selection, &text, bell_terminated);
+            }
             return;
         }
 
+        if self.mode.contains(WinMode::BRCKTPASTE) {
+            // the pasted text must not end the paste early
+            while let Some(i) = find_bytes(&text, PASTE_END) {
+                text.drain(i..i + PASTE_END.len());
+            }
+            text.splice(0..0, PASTE_START.iter().copied());
+            text.extend_from_slice(PASTE_END);
+        }
+        self.term_write(term, pty, &text);
+    }
+
+    fn selection_request(&mut self, xev: x11::XEvent) {
         let xev: &x11::XSelectionRequestEvent = x11::cast_event(&xev);
-        let text = self.sel_text.as_ref().unwrap();
+        let text = if xev.selection == self.clipboard {
+            &self.clip_text
+        } else {
+            &self.sel_text
+        };
+        let text = match text {
+            Some(text) => text,
+            None => return,
+        };
 
         let targets = x11::XInternAtom(self.dpy, "TARGETS", x11::False);
         if xev.target == targets {
@@ -777,8 +1052,10 @@ impl Win {
         }
     }
 
-    fn focus_change(&self, is_focus_in: bool) {
+    fn focus_change(&mut self, is_focus_in: bool) {
         self.im_focus(is_focus_in);
+        // releases while unfocused are not seen
+        self.keys_down.clear();
     }
 
     fn to_truecolor(&self, col: usize) -> x11::XftColor {
@@ -792,31 +1069,30 @@ impl Win {
             .expect("Failed to alloc truecolor")
     }
 
-    fn draw_cells(&self, cs: &[char], prop: GlyphProp, xp: usize, yp: usize) {
-        let GlyphProp { mut fg, bg, attr } = prop;
+    fn to_xftcolor(&self, col: usize) -> x11::XftColor {
+        if col & (1 << 24) > 0 {
+            // truecolor
+            self.to_truecolor(col)
+        } else {
+            self.colors[col]
+        }
+    }
+
+    // Each cell is a char with the zero-width chars attached to it.
+    fn draw_cells(&mut self, cs: &[(char, &str)], prop: GlyphProp, xp: usize, yp: usize) {
+        let GlyphProp { mut fg, bg, ul, attr } = prop;
         if attr.contains(GlyphAttr::BOLD) && fg < 8 {
             fg += 8;
         }
-        let charlen = if attr.contains(GlyphAttr::WIDE) {
-            cs.len() * 2
-        } else {
-            cs.len()
-        };
-        let width = charlen * self.cw;
-        let mut fg = if fg & (1 << 24) > 0 {
-            // truecolor
-            self.to_truecolor(fg)
-        } else {
-            self.colors[fg]
-        };
-        let bg = if bg & (1 << 24) > 0 {
-            // truecolor
-            self.to_truecolor(bg)
+        let cw = if attr.contains(GlyphAttr::WIDE) {
+            self.cw * 2
         } else {
-            self.colors[bg]
+            self.cw
         };
-        let font = self.font.get(attr);
-
+        let width = cs.len() * cw;
+        let mut fg = self.to_xftcolor(fg);
+        let bg = self.to_xftcolor(bg);
+        let mut ul = ul.map(|ul| self.to_xftcolor(ul));
         if attr.contains(GlyphAttr::FAINT) {
             let faintfg = x11::XRenderColor {
                 alpha: fg.color.alpha,
@@ -830,48 +1106,179 @@ impl Win {
                 println!("Failed to alloc truecolor for FAINT")
             }
         }
+        if attr.contains(GlyphAttr::RAPID_BLINK) {
+            self.rapid_blink = true;
+        }
         if attr.contains(GlyphAttr::INVISIBLE)
-            || (attr.contains(GlyphAttr::BLINK) && blink_hide())
+            || (attr.contains(GlyphAttr::BLINK) && blink_hide(BLINK_PERIOD_MS))
+            || (attr.contains(GlyphAttr::RAPID_BLINK) && blink_hide(RAPID_BLINK_PERIOD_MS))
         {
             fg = bg;
+            ul = None;
         }
 
         x11::XftDrawRect(self.draw, &bg, xp, yp, width, self.ch);
-        let idx = cs
-            .iter()
-            .map(|&c| x11::XftCharIndex(self.dpy, font, c))
-            .collect::<Vec<u32>>();
-        x11::XftDrawGlyphs(self.draw, &fg, font, xp, yp + self.ca, &idx);
+        // place each glyph at its cell, the font advance may not match
+        let mut specs = Vec::with_capacity(cs.len());
+        for (i, &(c, cluster)) in cs.iter().enumerate() {
+            let x = (xp + i * cw) as c_short;
+            let y = (yp + self.ca) as c_short;
+            // zero-width chars are drawn over the base char
+            for c in iter::once(c).chain(cluster.chars()) {
+                if cluster::is_invisible(c) {
+                    continue;
+                }
+                let (font, glyph) = self.font.lookup(self.dpy, self.scr, attr, c);
+                specs.push(x11::XftGlyphFontSpec { font, glyph, x, y });
+            }
+        }
+        x11::XftDrawGlyphFontSpec(self.draw, &fg, &specs);
 
-        /* Render underline and strikethrough. */
-        if attr.contains(GlyphAttr::UNDERLINE) {
-            let y = yp + self.font.ascent() + 1;
-            x11::XftDrawRect(self.draw, &fg, xp, y, width, 1);
+        /* Render underline, strikethrough and overline. */
+        if let Some(style) = attr.underline_style() {
+            self.draw_underline(&ul.unwrap_or(fg), style, xp, yp, width, cw);
         }
         if attr.contains(GlyphAttr::STRUCK) {
             let y = yp + (2 * self.font.ascent() / 3);
             x11::XftDrawRect(self.draw, &fg, xp, y, width, 1);
         }
+        if attr.contains(GlyphAttr::OVERLINE) {
+            x11::XftDrawRect(self.draw, &fg, xp, yp, width, 1);
+        }
+    }
+
+    fn draw_underline(
+        &self, color: &x11::XftColor, style: UnderlineStyle, xp: usize, yp: usize, width: usize,
+        cw: usize,
+    ) {
+        // keep the lines below the baseline inside the cell
+        let bottom = yp + self.ch - 1;
+        let y = cmp::min(yp + self.font.ascent() + 1, bottom);
+        match style {
+            UnderlineStyle::Single => x11::XftDrawRect(self.draw, color, xp, y, width, 1),
+            UnderlineStyle::Double => {
+                let y = cmp::min(y, bottom.saturating_sub(2));
+                x11::XftDrawRect(self.draw, color, xp, y, width, 1);
+                x11::XftDrawRect(self.draw, color, xp, y + 2, width, 1);
+            }
+            UnderlineStyle::Curly => {
+                // a wave of 4 pixels period
+                let y = cmp::min(y, bottom.saturating_sub(2));
+                for x in 0..width {
+                    let dy = [0, 1, 2, 1][x % 4];
+                    x11::XftDrawRect(self.draw, color, xp + x, y + dy, 1, 1);
+                }
+            }
+            UnderlineStyle::Dotted => {
+                for x in (0..width).step_by(2) {
+                    x11::XftDrawRect(self.draw, color, xp + x, y, 1, 1);
+                }
+            }
+            UnderlineStyle::Dashed => {
+                // a dash in the middle half of each cell
+                for x in (0..width).step_by(cw) {
+                    x11::XftDrawRect(self.draw, color, xp + x + cw / 4, y, cw / 2, 1);
+                }
+            }
+        }
     }
 
     fn draw_line(&mut self, term: &mut Term, y: usize) {
         let yp = y * self.ch;
         let mut x0 = 0;
-        let mut g0 = term.get_glyph(x0, y);
-        let mut cs = vec![g0.c];
+        let (_, mut g0) = term.get_cell(x0, y);
+        let mut cs = vec![(g0.c, term.cluster(&g0))];
 
         for x in x0 + 1..term.cols {
-            let g = term.get_glyph(x, y);
+            let (gx, g) = term.get_cell(x, y);
+            if gx != x {
+                // right half of a wide char, drawn with the left half
+                continue;
+            }
             if g0.prop == g.prop {
-                cs.push(g.c);
+                cs.push((g.c, term.cluster(&g)));
             } else {
                 self.draw_cells(&cs, g0.prop, x0 * self.cw, yp);
                 x0 = x;
                 g0 = g;
-                cs = vec![g0.c];
+                cs = vec![(g0.c, term.cluster(&g0))];
             }
         }
         self.draw_cells(&cs, g0.prop, x0 * self.cw, yp);
+        self.draw_images(term, 0..term.cols, y);
+    }
+
+    // Composite the image parts over the cells in xrange.
+    fn draw_images(&mut self, term: &Term, xrange: Range<usize>, y: usize) {
+        let mut x = xrange.start;
+        while x < xrange.end {
+            let cell = match term.get_glyph(x, y).image {
+                Some(cell) => cell,
+                None => {
+                    x += 1;
+                    continue;
+                }
+            };
+
+            // draw the cells showing adjacent parts of the image at once
+            let mut n = 1;
+            while x + n < xrange.end {
+                let next = ImageCell { col: cell.col + n as u16, ..cell };
+                if term.get_glyph(x + n, y).image != Some(next) {
+                    break;
+                }
+                n += 1;
+            }
+
+            if let Some(picture) = self.image_picture(term, cell.id) {
+                x11::XRenderComposite(
+                    self.dpy,
+                    picture,
+                    self.buf_pic,
+                    cell.col as usize * self.cw,
+                    cell.row as usize * self.ch,
+                    x * self.cw,
+                    y * self.ch,
+                    n * self.cw,
+                    self.ch,
+                );
+            }
+            x += n;
+        }
+    }
+
+    fn image_picture(&mut self, term: &Term, id: u32) -> Option<x11::Picture> {
+        if let Some(&picture) = self.pictures.get(&id) {
+            return Some(picture);
+        }
+
+        let image = term.get_image(id)?;
+        // premultiplied ARGB
+        let mut data = image
+            .data
+            .chunks_exact(4)
+            .map(|p| {
+                let a = p[3] as u32;
+                let premul = |c: u8| c as u32 * a / 255;
+                a << 24 | premul(p[0]) << 16 | premul(p[1]) << 8 | premul(p[2])
+            })
+            .collect::<Vec<u32>>();
+        let picture = x11::ximagepicture(
+            self.dpy,
+            self.win,
+            self.vis,
+            image.width,
+            image.height,
+            &mut data,
+            image.cell_width / self.cw as f64,
+            image.cell_height / self.ch as f64,
+        );
+        self.pictures.insert(id, picture);
+        Some(picture)
+    }
+
+    pub fn cell_size(&self) -> (usize, usize) {
+        (self.cw, self.ch)
     }
 
     fn finish_draw(&self, cols: usize, rows: usize) {
@@ -914,8 +1321,9 @@ impl Win {
         if self.sel_text.is_none() {
             return;
         }
+        self.clip_text = self.sel_text.clone();
 
-        let clipboard = x11::XInternAtom(self.dpy, "CLIPBOARD", x11::False);
+        let clipboard = self.clipboard;
         x11::XSetSelectionOwner(self.dpy, clipboard, self.win, time);
         x11::XSetSelectionOwner(self.dpy, x11::XA_PRIMARY, self.win, time);
         if x11::XGetSelectionOwner(self.dpy, clipboard) != self.win
@@ -925,6 +1333,51 @@ impl Win {
         }
     }
 
+    // Own the CLIPBOARD or PRIMARY selection with text set by the
+    // application, for OSC 52.
+    pub fn set_selection(&mut self, clipboard: bool, text: String) {
+        let selection = if clipboard {
+            self.clip_text = Some(text);
+            self.clipboard
+        } else {
+            self.sel_text = Some(text);
+            x11::XA_PRIMARY
+        };
+        x11::XSetSelectionOwner(self.dpy, selection, self.win, x11::CURRENT_TIME);
+    }
+
+    // Request the CLIPBOARD or PRIMARY selection, selection_notify replies
+    // to the OSC 52 query when it arrives.
+    pub fn query_selection(&mut self, clipboard: bool, bell_terminated: bool) {
+        let selection = if clipboard {
+            self.clipboard
+        } else {
+            x11::XA_PRIMARY
+        };
+        self.sel_query = Some((selection, bell_terminated));
+        x11::XConvertSelection(
+            self.dpy,
+            selection,
+            self.sel_type,
+            self.sel_query_prop,
+            self.win,
+            x11::CURRENT_TIME,
+        );
+    }
+
+    fn selection_reply(
+        &self, pty: &mut Pty, selection: x11::Atom, text: &[u8], bell_terminated: bool
+    ) {
+        let name = if selection == self.clipboard { 'c' } else { 'p' };
+        let mut v = format!("\x1b]52;{};{}", name, base64_encode(text)).into_bytes();
+        if bell_terminated {
+            v.push(0x07);
+        } else {
+            v.extend_from_slice(b"\x1b\\");
+        }
+        pty.write(&v);
+    }
+
     pub fn selection_paste(&mut self) {
         x11::XConvertSelection(
             self.dpy,
@@ -937,6 +1390,7 @@ impl Win {
     }
 
     fn term_write(&mut self, term: &mut Term, pty: &mut Pty, buf: &[u8]) {
+        term.reset_view();
         if self.mode.contains(WinMode::ECHO) {
             term.put_string(term_decode(buf));
         }
@@ -944,6 +1398,10 @@ impl Win {
     }
 }
 
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
 impl Drop for Win {
     fn drop(&mut self) {
         self.ime = None;
@@ -14,6 +14,7 @@ use anyhow::{anyhow, Result};
 use fontconfig::fontconfig as fc;
 use x11::xft;
 use x11::xlib;
+use x11::xrender;
 
 pub const XC_XTERM: c_uint = 152;
 
@@ -55,6 +56,7 @@ pub use xlib::ButtonReleaseMask as BUTTON_RELEASE_MASK;
 pub use xlib::ExposureMask as EXPOSURE_MASK;
 pub use xlib::FocusChangeMask as FOCUS_CHANGE_MASK;
 pub use xlib::KeyPressMask as KEY_PRESS_MASK;
+pub use xlib::KeyReleaseMask as KEY_RELEASE_MASK;
 pub use xlib::PointerMotionMask as POINTER_MOTION_MASK;
 pub use xlib::StructureNotifyMask as STRUCTURE_NOTIFY_MASK;
 pub use xlib::VisibilityChangeMask as VISIBILITY_CHANGE_MASK;
@@ -68,6 +70,7 @@ pub use xlib::Button1;
 pub use xlib::ButtonPress as BUTTON_PRESS;
 pub use xlib::ButtonRelease as BUTTON_RELEASE;
 pub use xlib::ControlMask;
+pub use xlib::Mod1Mask;
 pub use xlib::Mod4Mask;
 pub use xlib::ShiftMask;
 pub use xlib::XButtonEvent;
@@ -102,6 +105,7 @@ pub use x11::xrender::XGlyphInfo;
 
 pub use x11::xrender::XRenderColor;
 pub use xft::XftColor;
+pub use xft::XftGlyphFontSpec;
 
 pub use fc::FC_SLANT_ITALIC;
 pub use fc::FC_SLANT_ROMAN;
@@ -112,6 +116,8 @@ pub type Visual = *mut xlib::Visual;
 pub type XftFont = *mut xft::XftFont;
 pub type XftDraw = *mut xft::XftDraw;
 pub type FcPattern = *mut xft::FcPattern;
+pub type FcCharSet = *mut fc::FcCharSet;
+pub type Picture = xrender::Picture;
 
 // in C it's just a typedef for `void *`
 pub struct XVaNestedList<T> {
@@ -367,6 +373,14 @@ pub fn XLookupString(event: &mut XKeyEvent, buf: &mut [u8]) -> (KeySym, usize) {
     (ksym, cast(len))
 }
 
+pub fn XLookupKeysym(event: &mut XKeyEvent, index: c_int) -> KeySym {
+    unsafe { xlib::XLookupKeysym(event, index) }
+}
+
+pub fn XkbSetDetectableAutoRepeat(dpy: Display, detectable: bool) -> bool {
+    unsafe { xlib::XkbSetDetectableAutoRepeat(dpy, detectable as c_int, null_mut()) != 0 }
+}
+
 pub fn Xutf8LookupString(xic: XIC, event: &mut XKeyEvent, buf: &mut [u8]) -> Option<(KeySym, usize)> {
     let mut ksym: KeySym = 0;
     let mut status: c_int = 0;
@@ -519,6 +533,10 @@ pub fn XftFontOpenPattern(dpy: Display, pattern: FcPattern) -> Result<XftFont> {
     Ok(font)
 }
 
+pub fn XftFontClose(dpy: Display, font: XftFont) {
+    unsafe { xft::XftFontClose(dpy, font) }
+}
+
 pub fn font_ascent(font: XftFont) -> usize {
     unsafe { cast((*font).ascent) }
 }
@@ -589,24 +607,9 @@ pub fn XftDrawRect(d: XftDraw, color: &XftColor, x: usize, y: usize, width: usiz
     }
 }
 
-pub fn XftDrawGlyphs(
-    d: XftDraw,
-    color: &XftColor,
-    font: XftFont,
-    x: usize,
-    y: usize,
-    idx: &[c_uint],
-) {
+pub fn XftDrawGlyphFontSpec(d: XftDraw, color: &XftColor, specs: &[XftGlyphFontSpec]) {
     unsafe {
-        xft::XftDrawGlyphs(
-            d,
-            color,
-            font,
-            cast(x),
-            cast(y),
-            idx.as_ptr(),
-            cast(idx.len()),
-        );
+        xft::XftDrawGlyphFontSpec(d, color, specs.as_ptr(), cast(specs.len()));
     }
 }
 
@@ -614,12 +617,20 @@ pub fn XftCharIndex(dpy: Display, font: XftFont, c: char) -> c_uint {
     unsafe { xft::XftCharIndex(dpy, font, cast(c)) }
 }
 
+pub fn XftCharExists(dpy: Display, font: XftFont, c: char) -> bool {
+    unsafe { xft::XftCharExists(dpy, font, cast(c)) != 0 }
+}
+
 pub fn XftDrawChange(xft_draw: XftDraw, d: c_ulong) {
     unsafe {
         xft::XftDrawChange(xft_draw, d);
     }
 }
 
+pub fn FcPatternDuplicate(pattern: FcPattern) -> FcPattern {
+    unsafe { fc::FcPatternDuplicate(pattern as _) as _ }
+}
+
 pub fn FcPatternDestroy(pattern: FcPattern) {
     unsafe { fc::FcPatternDestroy(pattern as _) }
 }
@@ -636,6 +647,122 @@ pub fn FcPatternAddInteger(pattern: FcPattern, object: &CStr, i: c_int) {
     }
 }
 
+pub fn FcPatternAddBool(pattern: FcPattern, object: &CStr, b: bool) {
+    unsafe {
+        fc::FcPatternAddBool(pattern as _, object.as_ptr(), b as fc::FcBool);
+    }
+}
+
+pub fn FcPatternAddCharSet(pattern: FcPattern, object: &CStr, charset: FcCharSet) {
+    unsafe {
+        fc::FcPatternAddCharSet(pattern as _, object.as_ptr(), charset);
+    }
+}
+
+pub fn FcCharSetCreate() -> FcCharSet {
+    unsafe { fc::FcCharSetCreate() }
+}
+
+pub fn FcCharSetAddChar(charset: FcCharSet, c: char) {
+    unsafe {
+        fc::FcCharSetAddChar(charset, c as fc::FcChar32);
+    }
+}
+
+pub fn FcCharSetDestroy(charset: FcCharSet) {
+    unsafe { fc::FcCharSetDestroy(charset) }
+}
+
+pub fn XRenderCreatePicture(dpy: Display, d: c_ulong, vis: Visual) -> Picture {
+    unsafe {
+        let format = xrender::XRenderFindVisualFormat(dpy, vis);
+        xrender::XRenderCreatePicture(dpy, d, format, 0, null())
+    }
+}
+
+pub fn XRenderFreePicture(dpy: Display, picture: Picture) {
+    unsafe { xrender::XRenderFreePicture(dpy, picture) }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn XRenderComposite(
+    dpy: Display,
+    src: Picture,
+    dst: Picture,
+    src_x: usize,
+    src_y: usize,
+    dst_x: usize,
+    dst_y: usize,
+    width: usize,
+    height: usize,
+) {
+    unsafe {
+        xrender::XRenderComposite(
+            dpy,
+            xrender::PictOpOver,
+            src,
+            0,
+            dst,
+            cast(src_x),
+            cast(src_y),
+            0,
+            0,
+            cast(dst_x),
+            cast(dst_y),
+            cast(width),
+            cast(height),
+        );
+    }
+}
+
+/// Create an ARGB picture from premultiplied pixels in the native byte
+/// order, scaled down by sx and sy when composited.
+#[allow(clippy::too_many_arguments)]
+pub fn ximagepicture(
+    dpy: Display,
+    d: c_ulong,
+    vis: Visual,
+    width: usize,
+    height: usize,
+    data: &mut [u32],
+    sx: f64,
+    sy: f64,
+) -> Picture {
+    assert_eq!(data.len(), width * height);
+    let (w, h) = (width as c_uint, height as c_uint);
+    unsafe {
+        let pixmap = xlib::XCreatePixmap(dpy, d, w, h, 32);
+        let gc = xlib::XCreateGC(dpy, pixmap, 0, null_mut());
+        let image = xlib::XCreateImage(
+            dpy, vis, 32, xlib::ZPixmap, 0, data.as_mut_ptr() as *mut c_char, w, h, 32, 0,
+        );
+        xlib::XPutImage(dpy, pixmap, gc, image, 0, 0, 0, 0, w, h);
+        // the pixels are not owned by the image
+        (*image).data = null_mut();
+        xlib::XDestroyImage(image);
+        xlib::XFreeGC(dpy, gc);
+
+        let format = xrender::XRenderFindStandardFormat(dpy, xrender::PictStandardARGB32);
+        let picture = xrender::XRenderCreatePicture(dpy, pixmap, format, 0, null());
+        xlib::XFreePixmap(dpy, pixmap);
+
+        if sx != 1.0 || sy != 1.0 {
+            let fixed = |v: f64| (v * 65536.0) as xrender::XFixed;
+            let mut transform = xrender::XTransform {
+                matrix: [
+                    [fixed(sx), 0, 0],
+                    [0, fixed(sy), 0],
+                    [0, 0, fixed(1.0)],
+                ],
+            };
+            xrender::XRenderSetPictureTransform(dpy, picture, &mut transform);
+            let filter = CString::new("good").unwrap();
+            xrender::XRenderSetPictureFilter(dpy, picture, filter.as_ptr(), null_mut(), 0);
+        }
+        picture
+    }
+}
+
 pub fn xseticontitle(dpy: Display, win: Window, netwmiconname: Atom, title: &str) {
     if let Ok(p) = CString::new(title) {
         let mut pt = p.into_bytes_with_nul();